@@ -1,10 +1,57 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use movable_tree::{evan::EvanTree, martin::MartinTree, MovableTree};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use movable_tree::{evan::EvanTree, martin::MartinTree, MovableTree, MovableTreeAlgorithm, NodeID};
 use rand::{rngs::StdRng, Rng};
 
+/// Counts the bytes currently allocated so that the benches can report
+/// memory use next to time.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// The most bytes allocated at once since it was last reset.
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        let now = ALLOCATED.fetch_add(new_size, Ordering::Relaxed) + new_size;
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
 const CREATE_NODE_NUM: usize = 10000;
 const MOVE_NODE_NUM: usize = 1000;
 const MOVE_TIMES: usize = 10000;
+const DEEP_CHAIN_DEPTH: usize = 10000;
+const DEEP_MOVE_TIMES: usize = 1000;
+const LARGE_NODE_NUM: usize = 100000;
+const HOT_NODE_NUM: usize = 10;
+const HOT_MOVE_TIMES: usize = 5000;
+const DEEP_LOCAL_DEPTH: usize = 2000;
+const PEER_NUM: usize = 16;
+const PEER_MOVE_TIMES: usize = 100;
+const OFFLINE_MOVE_TIMES: usize = 2000;
+const LATE_ROUNDS: usize = 200;
+const LATE_WINDOWS: [usize; 2] = [5, 100];
 
 pub fn tree_move(c: &mut Criterion) {
     let mut b = c.benchmark_group(format!("tree create {} nodes", CREATE_NODE_NUM));
@@ -121,5 +168,379 @@ pub fn tree_move(c: &mut Criterion) {
     b.finish();
 }
 
-criterion_group!(benches, tree_move);
+fn deep_chain_realtime(mut tree_a: MovableTree<MartinTree>, mut tree_b: MovableTree<MartinTree>) {
+    let mut ids = vec![];
+    let mut parent = None;
+    for _ in 0..DEEP_CHAIN_DEPTH {
+        let id = tree_a.create(parent);
+        ids.push(id);
+        parent = Some(id);
+    }
+    tree_b.merge(&tree_a);
+    let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+    for t in 0..DEEP_MOVE_TIMES {
+        let i = rng.gen::<usize>() % DEEP_CHAIN_DEPTH;
+        let j = rng.gen::<usize>() % DEEP_CHAIN_DEPTH;
+        if t % 2 == 0 {
+            tree_a.mov(ids[i], ids[j]).unwrap_or_default();
+            tree_b.merge(&tree_a);
+        } else {
+            tree_b.mov(ids[i], ids[j]).unwrap_or_default();
+            tree_a.merge(&tree_b);
+        }
+    }
+}
+
+pub fn deep_tree(c: &mut Criterion) {
+    let mut b = c.benchmark_group(format!(
+        "realtime move in {} deep chain {} times",
+        DEEP_CHAIN_DEPTH, DEEP_MOVE_TIMES
+    ));
+    b.sample_size(10);
+    b.bench_function("martin", |b| {
+        b.iter(|| {
+            deep_chain_realtime(
+                MovableTree::<MartinTree>::new(0),
+                MovableTree::<MartinTree>::new(1),
+            )
+        })
+    });
+    b.bench_function("martin with ancestor index", |b| {
+        b.iter(|| {
+            let mut tree_a = MovableTree::<MartinTree>::new(0);
+            let mut tree_b = MovableTree::<MartinTree>::new(1);
+            tree_a.algorithm.enable_ancestor_index();
+            tree_b.algorithm.enable_ancestor_index();
+            deep_chain_realtime(tree_a, tree_b)
+        })
+    });
+    b.finish();
+}
+
+/// Two peers that see each other's moves a few ops late. Every round, `b`
+/// moves while `a` makes `window` concurrent moves, so each merge brings an
+/// op that sorts a few ops before the end of the log instead of after it.
+fn late_ops<T: MovableTreeAlgorithm>(window: usize) {
+    let mut a = MovableTree::<T>::new(0);
+    let mut b = MovableTree::<T>::new(1);
+    let ids: Vec<_> = (0..MOVE_NODE_NUM).map(|_| a.create(None)).collect();
+    b.merge(&a);
+    let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+    for _ in 0..LATE_ROUNDS {
+        let i = rng.gen::<usize>() % MOVE_NODE_NUM;
+        let j = rng.gen::<usize>() % MOVE_NODE_NUM;
+        b.mov(ids[i], ids[j]).unwrap_or_default();
+        for _ in 0..window {
+            let i = rng.gen::<usize>() % MOVE_NODE_NUM;
+            let j = rng.gen::<usize>() % MOVE_NODE_NUM;
+            a.mov(ids[i], ids[j]).unwrap_or_default();
+        }
+        a.merge(&b);
+        b.merge(&a);
+    }
+}
+
+pub fn late_merge(c: &mut Criterion) {
+    let mut b = c.benchmark_group(format!("merge moves that are late {} times", LATE_ROUNDS));
+    b.sample_size(10);
+    for window in LATE_WINDOWS {
+        b.bench_function(format!("martin {} ops late", window), |b| {
+            b.iter(|| late_ops::<MartinTree>(window))
+        });
+    }
+    b.finish();
+}
+
+fn large_tree<T: MovableTreeAlgorithm>() -> (MovableTree<T>, Vec<NodeID>) {
+    let mut tree = MovableTree::<T>::new(0);
+    let mut ids = vec![];
+    let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+    for i in 0..LARGE_NODE_NUM {
+        let parent = (i > 0).then(|| ids[rng.gen::<usize>() % i]);
+        ids.push(tree.create(parent));
+    }
+    (tree, ids)
+}
+
+/// Print the bytes held by the algorithm alone, without the op log kept by
+/// `MovableTree`, for a tree of `LARGE_NODE_NUM` nodes.
+fn report_memory<T: MovableTreeAlgorithm>(name: &str) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let algorithm = large_tree::<T>().0.algorithm;
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!(
+        "memory of {} with {} nodes: {} bytes, {} bytes per node",
+        name,
+        LARGE_NODE_NUM,
+        bytes,
+        bytes / LARGE_NODE_NUM
+    );
+    drop(algorithm);
+}
+
+pub fn large(c: &mut Criterion) {
+    report_memory::<EvanTree>("evan");
+    report_memory::<MartinTree>("martin");
+
+    let mut b = c.benchmark_group(format!("large tree {} nodes", LARGE_NODE_NUM));
+    b.sample_size(10);
+    b.bench_function("evan create", |b| b.iter(large_tree::<EvanTree>));
+    b.bench_function("martin create", |b| b.iter(large_tree::<MartinTree>));
+    let (tree, ids) = large_tree::<EvanTree>();
+    b.bench_function("evan parent lookup", |b| {
+        b.iter(|| {
+            ids.iter()
+                .filter_map(|&id| tree.algorithm.parent(id))
+                .count()
+        })
+    });
+    let (tree, ids) = large_tree::<MartinTree>();
+    b.bench_function("martin parent lookup", |b| {
+        b.iter(|| {
+            ids.iter()
+                .filter_map(|&id| tree.algorithm.parent(id))
+                .count()
+        })
+    });
+    b.finish();
+}
+
+/// A few hot nodes are moved under many different parents, so each of them
+/// ends up with hundreds of distinct edges.
+pub fn many_edges(c: &mut Criterion) {
+    let mut b = c.benchmark_group(format!(
+        "move {} nodes under {} parents {} times",
+        HOT_NODE_NUM, MOVE_NODE_NUM, HOT_MOVE_TIMES
+    ));
+    b.sample_size(10);
+    b.bench_function("evan", |b| {
+        b.iter(|| {
+            let mut tree = MovableTree::<EvanTree>::new(0);
+            let mut ids = vec![];
+            for _ in 0..MOVE_NODE_NUM {
+                ids.push(tree.create(None));
+            }
+            let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+            for _ in 0..HOT_MOVE_TIMES {
+                let i = rng.gen::<usize>() % HOT_NODE_NUM;
+                let j = rng.gen::<usize>() % MOVE_NODE_NUM;
+                tree.mov(ids[i], ids[j]).unwrap_or_default();
+            }
+        })
+    });
+    b.finish();
+}
+
+/// Run `f` once and print the peak bytes allocated while it ran, on top of
+/// what was allocated before, and the encoded size of the op log of the tree
+/// it returns.
+fn report<T: MovableTreeAlgorithm>(scenario: &str, name: &str, f: impl FnOnce() -> MovableTree<T>) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let tree = f();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    println!(
+        "{} ({}): peak memory {} bytes, op log {} bytes",
+        scenario,
+        name,
+        peak,
+        tree.export().len()
+    );
+}
+
+fn chain<T: MovableTreeAlgorithm>(depth: usize) -> (MovableTree<T>, Vec<NodeID>) {
+    let mut tree = MovableTree::<T>::new(0);
+    let mut ids: Vec<NodeID> = vec![];
+    for _ in 0..depth {
+        ids.push(tree.create(ids.last().copied()));
+    }
+    (tree, ids)
+}
+
+/// Move random nodes of a chain under other random nodes, which mostly
+/// touches long paths to the root.
+fn deep_moves<T: MovableTreeAlgorithm>() -> MovableTree<T> {
+    let (mut tree, ids) = chain::<T>(DEEP_LOCAL_DEPTH);
+    let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+    for _ in 0..DEEP_MOVE_TIMES {
+        let i = rng.gen::<usize>() % DEEP_LOCAL_DEPTH;
+        let j = rng.gen::<usize>() % DEEP_LOCAL_DEPTH;
+        tree.mov(ids[i], ids[j]).unwrap_or_default();
+    }
+    tree
+}
+
+/// Peers that share `MOVE_NODE_NUM` nodes and each made `moves` moves
+/// without syncing.
+fn diverged<T: MovableTreeAlgorithm>(peers: usize, moves: usize) -> Vec<MovableTree<T>> {
+    let mut trees: Vec<MovableTree<T>> = (0..peers as u64).map(MovableTree::new).collect();
+    let ids: Vec<_> = (0..MOVE_NODE_NUM).map(|_| trees[0].create(None)).collect();
+    let (first, rest) = trees.split_first_mut().unwrap();
+    for tree in rest {
+        tree.merge(first);
+    }
+    let mut rng: StdRng = rand::SeedableRng::seed_from_u64(0);
+    for tree in trees.iter_mut() {
+        for _ in 0..moves {
+            let a = rng.gen::<usize>() % MOVE_NODE_NUM;
+            let b = rng.gen::<usize>() % MOVE_NODE_NUM;
+            tree.mov(ids[a], ids[b]).unwrap_or_default();
+        }
+    }
+    trees
+}
+
+/// Merge every peer into the first one, then the first one into every peer.
+fn gossip<T: MovableTreeAlgorithm>(mut trees: Vec<MovableTree<T>>) -> MovableTree<T> {
+    let (first, rest) = trees.split_first_mut().unwrap();
+    for tree in rest.iter() {
+        first.merge(tree);
+    }
+    for tree in rest.iter_mut() {
+        tree.merge(first);
+    }
+    trees.pop().unwrap()
+}
+
+fn merge_offline<T: MovableTreeAlgorithm>(mut trees: Vec<MovableTree<T>>) -> MovableTree<T> {
+    let b = trees.pop().unwrap();
+    let mut a = trees.pop().unwrap();
+    a.merge(&b);
+    a
+}
+
+pub fn scenarios(c: &mut Criterion) {
+    report("deep chain moves", "evan", deep_moves::<EvanTree>);
+    report("deep chain moves", "martin", deep_moves::<MartinTree>);
+    let mut b = c.benchmark_group(format!(
+        "move in {} deep chain {} times",
+        DEEP_LOCAL_DEPTH, DEEP_MOVE_TIMES
+    ));
+    b.sample_size(10);
+    b.bench_function("evan", |b| b.iter(deep_moves::<EvanTree>));
+    b.bench_function("martin", |b| b.iter(deep_moves::<MartinTree>));
+    b.finish();
+
+    let name = format!(
+        "{} peers move {} times each then sync",
+        PEER_NUM, PEER_MOVE_TIMES
+    );
+    report(&name, "evan", || {
+        gossip(diverged::<EvanTree>(PEER_NUM, PEER_MOVE_TIMES))
+    });
+    report(&name, "martin", || {
+        gossip(diverged::<MartinTree>(PEER_NUM, PEER_MOVE_TIMES))
+    });
+    let mut b = c.benchmark_group(name);
+    b.sample_size(10);
+    b.bench_function("evan", |b| {
+        b.iter_batched(
+            || diverged::<EvanTree>(PEER_NUM, PEER_MOVE_TIMES),
+            gossip,
+            BatchSize::LargeInput,
+        )
+    });
+    b.bench_function("martin", |b| {
+        b.iter_batched(
+            || diverged::<MartinTree>(PEER_NUM, PEER_MOVE_TIMES),
+            gossip,
+            BatchSize::LargeInput,
+        )
+    });
+    b.finish();
+
+    let name = format!(
+        "merge 2 peers offline for {} moves each",
+        OFFLINE_MOVE_TIMES
+    );
+    report(&name, "evan", || {
+        merge_offline(diverged::<EvanTree>(2, OFFLINE_MOVE_TIMES))
+    });
+    report(&name, "martin", || {
+        merge_offline(diverged::<MartinTree>(2, OFFLINE_MOVE_TIMES))
+    });
+    let mut b = c.benchmark_group(name);
+    b.sample_size(10);
+    b.bench_function("evan", |b| {
+        b.iter_batched(
+            || diverged::<EvanTree>(2, OFFLINE_MOVE_TIMES),
+            merge_offline,
+            BatchSize::LargeInput,
+        )
+    });
+    b.bench_function("martin", |b| {
+        b.iter_batched(
+            || diverged::<MartinTree>(2, OFFLINE_MOVE_TIMES),
+            merge_offline,
+            BatchSize::LargeInput,
+        )
+    });
+    b.finish();
+}
+
+/// Turning the tree into a `TreeNode` and printing it.
+pub fn materialize(c: &mut Criterion) {
+    let mut b = c.benchmark_group(format!("materialize {} nodes", LARGE_NODE_NUM));
+    b.sample_size(10);
+    let (evan, _) = large_tree::<EvanTree>();
+    let (martin, _) = large_tree::<MartinTree>();
+    b.bench_function("evan get_root", |b| b.iter(|| evan.algorithm.get_root()));
+    b.bench_function("martin get_root", |b| {
+        b.iter(|| martin.algorithm.get_root())
+    });
+    b.bench_function("evan to_string", |b| b.iter(|| evan.to_string()));
+    b.bench_function("martin to_string", |b| b.iter(|| martin.to_string()));
+    b.finish();
+}
+
+pub fn encoding(c: &mut Criterion) {
+    let (evan, _) = large_tree::<EvanTree>();
+    let (martin, _) = large_tree::<MartinTree>();
+    let ops = martin.export();
+    let state = evan.export_state();
+    println!(
+        "encoding of {} nodes: ops {} bytes, evan state {} bytes",
+        LARGE_NODE_NUM,
+        ops.len(),
+        state.len()
+    );
+    let mut b = c.benchmark_group(format!("encode {} nodes", LARGE_NODE_NUM));
+    b.sample_size(10);
+    b.bench_function("export ops", |b| b.iter(|| martin.export()));
+    b.bench_function("evan export state", |b| b.iter(|| evan.export_state()));
+    b.bench_function("evan import ops", |b| {
+        b.iter_batched(
+            || MovableTree::<EvanTree>::new(1),
+            |mut tree| tree.import(&ops).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    b.bench_function("martin import ops", |b| {
+        b.iter_batched(
+            || MovableTree::<MartinTree>::new(1),
+            |mut tree| tree.import(&ops).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    b.bench_function("evan import state", |b| {
+        b.iter_batched(
+            || MovableTree::<EvanTree>::new(1),
+            |mut tree| tree.import_state(&state).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    b.finish();
+}
+
+criterion_group!(
+    benches,
+    tree_move,
+    deep_tree,
+    late_merge,
+    large,
+    many_edges,
+    scenarios,
+    materialize,
+    encoding
+);
 criterion_main!(benches);
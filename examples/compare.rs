@@ -0,0 +1,73 @@
+use movable_tree::analysis::{compare, Comparison, Step};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Random edits by `peers` peers, each step syncing one pair of peers with
+/// probability `sync`.
+fn trace(rng: &mut StdRng, peers: usize, steps: usize, sync: f64) -> Vec<Step> {
+    let mut created = 0;
+    let mut trace = Vec::with_capacity(steps);
+    for _ in 0..steps {
+        let peer = rng.gen_range(0..peers);
+        let node = |rng: &mut StdRng| match created {
+            0 => None,
+            n => rng.gen_ratio(9, 10).then(|| rng.gen_range(0..n)),
+        };
+        if rng.gen_bool(sync) {
+            trace.push(Step::Sync {
+                from: rng.gen_range(0..peers),
+                to: peer,
+            });
+        } else if created < 4 || rng.gen_ratio(1, 5) {
+            trace.push(Step::Create {
+                peer,
+                parent: node(rng),
+            });
+            created += 1;
+        } else {
+            trace.push(Step::Move {
+                peer,
+                target: rng.gen_range(0..created),
+                parent: node(rng),
+            });
+        }
+    }
+    trace
+}
+
+fn main() {
+    let scenarios = [
+        ("2 peers, sync often", 2, 0.5),
+        ("2 peers, sync rarely", 2, 0.05),
+        ("5 peers, sync often", 5, 0.5),
+        ("5 peers, sync rarely", 5, 0.05),
+    ];
+    println!(
+        "{:<22} {:>6} {:>8} {:>11} {:>11} {:>9}",
+        "scenario", "nodes", "diverged", "martin kept", "evan kept", "moves"
+    );
+    for (name, peers, sync) in scenarios {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut total = Comparison::default();
+        let mut diverged = 0;
+        for _ in 0..100 {
+            let c = compare(peers, &trace(&mut rng, peers, 200, sync));
+            total.nodes += c.nodes;
+            total.martin.kept += c.martin.kept;
+            total.martin.overridden += c.martin.overridden;
+            total.evan.kept += c.evan.kept;
+            total.evan.overridden += c.evan.overridden;
+            diverged += c.diverged.len();
+        }
+        let moves = total.martin.kept + total.martin.overridden;
+        let percent = |n: usize| 100.0 * n as f64 / moves.max(1) as f64;
+        println!(
+            "{:<22} {:>6} {:>8} {:>10.1}% {:>10.1}% {:>9}",
+            name,
+            total.nodes,
+            diverged,
+            percent(total.martin.kept),
+            percent(total.evan.kept),
+            moves,
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Instant;
+
+use movable_tree::{
+    evan::EvanTree,
+    martin::MartinTree,
+    model::{check, Move},
+    MovableTreeAlgorithm,
+};
+
+fn run<T: MovableTreeAlgorithm + Clone>(name: &str, scripts: &[Vec<Move>]) {
+    let start = Instant::now();
+    match check::<T>(4, scripts) {
+        Ok(states) => println!("{}: {} states in {:?}", name, states, start.elapsed()),
+        Err(e) => println!("{}: {}", name, e),
+    }
+}
+
+fn main() {
+    let m = |target, parent| Move {
+        target,
+        parent: Some(parent),
+    };
+    // three peers moving four nodes into each other, so that most
+    // interleavings contain a cycle
+    let scripts = [
+        vec![m(0, 1), m(1, 2), m(2, 3)],
+        vec![m(1, 0), m(3, 2), m(2, 1)],
+        vec![m(3, 0), m(0, 2), m(1, 3)],
+    ];
+    run::<MartinTree>("martin", &scripts);
+    run::<EvanTree>("evan", &scripts);
+}
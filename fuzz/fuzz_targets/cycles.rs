@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use movable_tree::fuzz::{fuzz_cycles, Pattern};
+
+fuzz_target!(|patterns: Vec<Pattern>| {
+    fuzz_cycles(3, &mut patterns.clone())
+});
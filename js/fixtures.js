@@ -0,0 +1,117 @@
+// Records random sessions of the demo as fixtures for the Rust p2pdb
+// adapter. Run with `node js/fixtures.js` from the repository root.
+//
+// Each fixture holds every row written by every peer, in the order they
+// were written, and the parent of every node as computed by `Tree` from the
+// rows that won. Nodes that aren't under the root have a null parent.
+
+const fs = require("fs");
+const path = require("path");
+
+// index.js also contains the browser UI, so only load the algorithm
+const index = fs.readFileSync(path.join(__dirname, "index.js"), "utf8");
+const algorithm = index.slice(
+  0,
+  index.indexOf("// The code above is everything relevant")
+);
+const p2pdb = fs.readFileSync(path.join(__dirname, "p2pdb.js"), "utf8");
+const { DB, UndoRedo, Tree, isNodeUnderOtherNode } = new Function(
+  p2pdb + algorithm + "return { DB, UndoRedo, Tree, isNodeUnderOtherNode };"
+)();
+
+function mulberry32(seed) {
+  return () => {
+    seed = (seed + 0x6d2b79f5) | 0;
+    let t = Math.imul(seed ^ (seed >>> 15), 1 | seed);
+    t = (t + Math.imul(t ^ (t >>> 7), 61 | t)) ^ t;
+    return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+  };
+}
+
+function record({ seed, peers: peerCount, steps }) {
+  const random = mulberry32(seed);
+  const pick = (items) => items[Math.floor(random() * items.length)];
+
+  // A slow clock, so that peers often write with the same timestamp
+  let ticks = 0;
+  Date.now = () => Math.floor(ticks++ / 4);
+
+  const rows = [];
+  const peers = [];
+  for (let i = 0; i < peerCount; i++) {
+    const db = new DB("Peer " + (i + 1));
+    const peer = { db, tree: new Tree(db), inbox: [] };
+    peer.undoRedo = new UndoRedo(db);
+    db.afterApply(({ op, origin }) => {
+      if (origin === "remote") return;
+      rows.push({ ...op });
+      for (const other of peers) if (other !== peer) other.inbox.push(op);
+    });
+    peers.push(peer);
+  }
+
+  for (let step = 0; step < steps; step++) {
+    const peer = pick(peers);
+    const { db, tree, undoRedo } = peer;
+    const nodes = [...tree.nodes.values()].filter((n) => n !== tree.root);
+    const action = random();
+    if (action < 0.2 || nodes.length < 2) {
+      const id = Math.floor(random() * 36 ** 8).toString(36);
+      undoRedo.batch(() => db.set(id, pick(nodes.concat(tree.root)).id, 0));
+    } else if (action < 0.6) {
+      // The same rule as dragging in the demo
+      const child = pick(nodes);
+      const parents = [...tree.nodes.values()].filter(
+        (p) => child.parent !== p && !isNodeUnderOtherNode(p, child)
+      );
+      if (parents.length) {
+        const parent = pick(parents);
+        undoRedo.batch(() => tree.addChildToParent(child.id, parent.id));
+      }
+    } else if (action < 0.7) {
+      undoRedo.undo();
+    } else if (action < 0.75) {
+      undoRedo.redo();
+    } else {
+      // Deliver some of the packets in flight, in any order
+      const inbox = peer.inbox;
+      peer.inbox = [];
+      for (const op of inbox) {
+        if (random() < 0.7) db.apply(op, "remote");
+        else peer.inbox.push(op);
+      }
+    }
+  }
+
+  // `Tree` applies every row it sees, even one the database drops for being
+  // older, so build the tree from the rows that won instead.
+  const db = new DB("replay");
+  for (const row of rows) db.apply(row, "remote");
+  const final = new DB("final");
+  const finalTree = new Tree(final);
+  for (const [id, row] of db._rows) {
+    for (const [key, field] of row) {
+      final.apply({ id, key, ...field }, "remote");
+    }
+  }
+
+  const parents = {};
+  for (const node of finalTree.nodes.values()) {
+    if (node === finalTree.root) continue;
+    const rooted = isNodeUnderOtherNode(node, finalTree.root);
+    parents[node.id] = rooted ? node.parent.id : null;
+  }
+  return { rows, parents };
+}
+
+const dir = path.join(__dirname, "..", "tests", "fixtures", "p2pdb");
+fs.mkdirSync(dir, { recursive: true });
+const sessions = [
+  { name: "two-peers", seed: 1, peers: 2, steps: 60 },
+  { name: "four-peers", seed: 2, peers: 4, steps: 120 },
+  { name: "three-peers", seed: 3, peers: 3, steps: 200 },
+];
+for (const { name, ...options } of sessions) {
+  const fixture = JSON.stringify(record(options), null, 1);
+  fs.writeFileSync(path.join(dir, name + ".json"), fixture + "\n");
+}
@@ -0,0 +1,182 @@
+//! Replay the same user edits through both algorithms to compare how they
+//! resolve conflicts.
+
+use fxhash::FxHashSet;
+
+use crate::{
+    array_mut_ref, evan::EvanTree, martin::MartinTree, MovableTree, MovableTreeAlgorithm, NodeID,
+    ID, ROOT_ID,
+};
+
+/// One step of a trace. Peers are numbered from 0, and nodes by the order of
+/// the `Create` steps that made them. A `None` parent is the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Create {
+        peer: usize,
+        parent: Option<usize>,
+    },
+    Move {
+        peer: usize,
+        target: usize,
+        parent: Option<usize>,
+    },
+    /// `to` merges everything `from` has.
+    Sync {
+        from: usize,
+        to: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// Nodes in the final tree, without the root.
+    pub nodes: usize,
+    /// Steps a peer could not make, because it did not know one of the nodes
+    /// yet or because the move would create a cycle in either tree.
+    pub rejected: usize,
+    /// Moves replaced by a later move of the same node made by a peer that
+    /// had already seen them. These don't conflict with anything, so they are
+    /// not counted as kept or overridden.
+    pub superseded: usize,
+    pub martin: MoveCounts,
+    pub evan: MoveCounts,
+    /// Nodes whose final parent differs between the two algorithms.
+    pub diverged: Vec<Divergence>,
+}
+
+/// What happened to the moves that were the latest move of their node as far
+/// as the peer that made them knew.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MoveCounts {
+    /// The node ended up under the parent the move put it under.
+    pub kept: usize,
+    /// The node ended up elsewhere, because of a concurrent move or a cycle.
+    pub overridden: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub node: NodeID,
+    pub martin: Option<NodeID>,
+    pub evan: Option<NodeID>,
+}
+
+/// Replay `trace` on `peers` peers for both algorithms, then sync every peer
+/// and compare the final trees. A step only takes effect if the peer can make
+/// it in both trees, so both algorithms always see the same user edits.
+pub fn compare(peers: usize, trace: &[Step]) -> Comparison {
+    let mut martin: Vec<MovableTree<MartinTree>> =
+        (0..peers as u64).map(MovableTree::new).collect();
+    let mut evan: Vec<MovableTree<EvanTree>> = (0..peers as u64).map(MovableTree::new).collect();
+    let mut nodes: Vec<Option<NodeID>> = Vec::new();
+    let mut moves: Vec<(ID, NodeID, NodeID)> = Vec::new();
+    let mut superseded = FxHashSet::default();
+    let mut ans = Comparison::default();
+    for &step in trace {
+        match step {
+            Step::Create { peer, parent } => {
+                let Some(parent) = known(&martin[peer], &nodes, parent) else {
+                    nodes.push(None);
+                    ans.rejected += 1;
+                    continue;
+                };
+                let id = martin[peer].create(Some(parent));
+                assert_eq!(id, evan[peer].create(Some(parent)));
+                nodes.push(Some(id));
+            }
+            Step::Move {
+                peer,
+                target,
+                parent,
+            } => {
+                let (Some(target), Some(parent)) = (
+                    known(&martin[peer], &nodes, Some(target)),
+                    known(&martin[peer], &nodes, parent),
+                ) else {
+                    ans.rejected += 1;
+                    continue;
+                };
+                if martin[peer].is_ancestor_of(target, parent)
+                    || evan[peer].is_ancestor_of(target, parent)
+                {
+                    ans.rejected += 1;
+                    continue;
+                }
+                // every move of the node this peer has seen is replaced
+                superseded.extend(
+                    martin[peer]
+                        .history(target)
+                        .iter()
+                        .filter(|r| !r.create)
+                        .map(|r| r.id),
+                );
+                martin[peer].mov(target, parent).unwrap();
+                evan[peer].mov(target, parent).unwrap();
+                let id = martin[peer].history(target).last().unwrap().id;
+                moves.push((id, target, parent));
+            }
+            Step::Sync { from, to } => {
+                if from != to {
+                    let (a, b) = array_mut_ref!(&mut martin, [to, from]);
+                    a.merge(b);
+                    let (a, b) = array_mut_ref!(&mut evan, [to, from]);
+                    a.merge(b);
+                }
+            }
+        }
+    }
+
+    for i in 1..peers {
+        let (a, b) = array_mut_ref!(&mut martin, [0, i]);
+        a.merge(b);
+        let (a, b) = array_mut_ref!(&mut evan, [0, i]);
+        a.merge(b);
+    }
+    let (martin, evan) = (&martin[0], &evan[0]);
+    for (id, target, parent) in moves {
+        if superseded.contains(&id) {
+            ans.superseded += 1;
+            continue;
+        }
+        for (tree, counts) in [
+            (martin.algorithm.parent(target), &mut ans.martin),
+            (evan.algorithm.parent(target), &mut ans.evan),
+        ] {
+            match tree == Some(parent) {
+                true => counts.kept += 1,
+                false => counts.overridden += 1,
+            }
+        }
+    }
+    let mut all = martin.nodes();
+    all.sort();
+    ans.nodes = all.len();
+    for node in all {
+        let (m, e) = (martin.algorithm.parent(node), evan.algorithm.parent(node));
+        if m != e {
+            ans.diverged.push(Divergence {
+                node,
+                martin: m,
+                evan: e,
+            });
+        }
+    }
+    ans
+}
+
+/// The ID of the node `node` refers to, if the peer knows it.
+fn known(
+    tree: &MovableTree<MartinTree>,
+    nodes: &[Option<NodeID>],
+    node: Option<usize>,
+) -> Option<NodeID> {
+    match node {
+        None => Some(ROOT_ID),
+        Some(i) => nodes
+            .get(i)
+            .copied()
+            .flatten()
+            .filter(|&id| tree.algorithm.parent(id).is_some()),
+    }
+}
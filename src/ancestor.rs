@@ -0,0 +1,154 @@
+use std::hash::Hash;
+
+use fxhash::FxHashMap;
+
+use crate::NodeID;
+
+const NIL: usize = 0;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Link {
+    // Either the parent in the splay tree or, for the root of a splay tree,
+    // the path-parent pointer to the node the preferred path hangs from.
+    parent: usize,
+    children: [usize; 2],
+}
+
+/// A link-cut tree mirroring a parent map, answering ancestor queries in
+/// amortized `O(log n)` regardless of how deep the tree is.
+///
+/// It must be kept in sync by calling [`AncestorIndex::set_parent`] every time
+/// the parent of a node changes, and the mirrored parent map must never
+/// contain a cycle.
+#[derive(Debug, Clone)]
+pub struct AncestorIndex<K = NodeID> {
+    ids: FxHashMap<K, usize>,
+    links: Vec<Link>,
+}
+
+impl<K: Copy + Eq + Hash> Default for AncestorIndex<K> {
+    fn default() -> Self {
+        AncestorIndex {
+            ids: FxHashMap::default(),
+            // index 0 is the NIL sentinel
+            links: vec![Link::default()],
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash> AncestorIndex<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn index_of(&mut self, node: K) -> usize {
+        let links = &mut self.links;
+        *self.ids.entry(node).or_insert_with(|| {
+            links.push(Link::default());
+            links.len() - 1
+        })
+    }
+
+    pub fn set_parent(&mut self, node: K, parent: Option<K>) {
+        let x = self.index_of(node);
+        let p = parent.map(|p| self.index_of(p));
+        let links = &mut self.links;
+        cut(links, x);
+        if let Some(p) = p {
+            debug_assert!(find_root(links, p) != x, "link would create a cycle");
+            access(links, x);
+            links[x].parent = p;
+        }
+    }
+
+    /// Whether `maybe_ancestor` is `node` itself or one of its ancestors.
+    /// Queries restructure the splay trees, hence `&mut self`.
+    pub fn is_ancestor_of(&mut self, maybe_ancestor: K, node: K) -> bool {
+        if maybe_ancestor == node {
+            return true;
+        }
+        let (Some(&a), Some(&b)) = (self.ids.get(&maybe_ancestor), self.ids.get(&node)) else {
+            return false;
+        };
+        let links = &mut self.links;
+        if find_root(links, a) != find_root(links, b) {
+            return false;
+        }
+        access(links, b);
+        access(links, a) == a
+    }
+}
+
+fn is_splay_root(links: &[Link], x: usize) -> bool {
+    let p = links[x].parent;
+    p == NIL || (links[p].children[0] != x && links[p].children[1] != x)
+}
+
+fn rotate(links: &mut [Link], x: usize) {
+    let p = links[x].parent;
+    let g = links[p].parent;
+    let dir = (links[p].children[1] == x) as usize;
+    let b = links[x].children[dir ^ 1];
+    if !is_splay_root(links, p) {
+        let p_dir = (links[g].children[1] == p) as usize;
+        links[g].children[p_dir] = x;
+    }
+    links[x].parent = g;
+    links[x].children[dir ^ 1] = p;
+    links[p].parent = x;
+    links[p].children[dir] = b;
+    if b != NIL {
+        links[b].parent = p;
+    }
+}
+
+fn splay(links: &mut [Link], x: usize) {
+    while !is_splay_root(links, x) {
+        let p = links[x].parent;
+        if !is_splay_root(links, p) {
+            let g = links[p].parent;
+            let zig_zig = (links[g].children[1] == p) == (links[p].children[1] == x);
+            if zig_zig {
+                rotate(links, p);
+            } else {
+                rotate(links, x);
+            }
+        }
+        rotate(links, x);
+    }
+}
+
+/// Make the path from the root to `x` preferred. Returns the last node where
+/// the walk joined the root's preferred path, which is the lowest common
+/// ancestor of `x` and the previously accessed node.
+fn access(links: &mut [Link], x: usize) -> usize {
+    let mut last = NIL;
+    let mut y = x;
+    while y != NIL {
+        splay(links, y);
+        links[y].children[1] = last;
+        last = y;
+        y = links[y].parent;
+    }
+    splay(links, x);
+    last
+}
+
+fn find_root(links: &mut [Link], x: usize) -> usize {
+    access(links, x);
+    let mut root = x;
+    while links[root].children[0] != NIL {
+        root = links[root].children[0];
+    }
+    splay(links, root);
+    root
+}
+
+fn cut(links: &mut [Link], x: usize) {
+    access(links, x);
+    let above = links[x].children[0];
+    if above != NIL {
+        links[above].parent = NIL;
+        links[x].children[0] = NIL;
+    }
+}
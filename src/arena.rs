@@ -0,0 +1,79 @@
+use fxhash::FxHashMap;
+
+use crate::{NodeKey, TreeNode};
+
+/// Index used for "no node", e.g. the parent of the root.
+pub(crate) const NIL: u32 = u32::MAX;
+
+/// Interns node keys into dense `u32` indices so that the algorithms can keep
+/// their per-node state in `Vec`s instead of hash maps keyed by the node.
+///
+/// Indices are only meaningful within one arena: two replicas may intern the
+/// same key under different indices, so anything that has to be deterministic
+/// across peers must compare keys, not indices.
+#[derive(Debug, Clone)]
+pub(crate) struct Arena<K> {
+    ids: FxHashMap<K, u32>,
+    keys: Vec<K>,
+}
+
+impl<K: NodeKey> Arena<K> {
+    /// The root is always interned first, at index 0.
+    pub const ROOT: u32 = 0;
+
+    pub fn new() -> Self {
+        let mut arena = Arena {
+            ids: FxHashMap::default(),
+            keys: Vec::new(),
+        };
+        arena.intern(K::root());
+        arena
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn get(&self, key: &K) -> Option<u32> {
+        self.ids.get(key).copied()
+    }
+
+    /// Returns the index of `key`, and whether it was interned just now.
+    pub fn intern(&mut self, key: K) -> (u32, bool) {
+        if let Some(&idx) = self.ids.get(&key) {
+            return (idx, false);
+        }
+        let idx = u32::try_from(self.keys.len())
+            .ok()
+            .filter(|&idx| idx < NIL - 1)
+            .expect("too many nodes");
+        self.ids.insert(key, idx);
+        self.keys.push(key);
+        (idx, true)
+    }
+
+    pub fn key(&self, idx: u32) -> K {
+        self.keys[idx as usize]
+    }
+
+    /// Build the tree under the root from the dense parent of every node.
+    /// Nodes whose parent is [`NIL`] or unknown are left out.
+    pub fn tree_node(&self, parent: impl Fn(u32) -> Option<u32>) -> TreeNode<K> {
+        let mut children = vec![Vec::new(); self.len()];
+        for idx in 0..self.len() as u32 {
+            if let Some(p) = parent(idx) {
+                children[p as usize].push(idx);
+            }
+        }
+        self.build(Self::ROOT, &children)
+    }
+
+    fn build(&self, idx: u32, children: &[Vec<u32>]) -> TreeNode<K> {
+        let mut nodes: Vec<_> = children[idx as usize]
+            .iter()
+            .map(|&child| self.build(child, children))
+            .collect();
+        nodes.sort();
+        TreeNode::new(self.key(idx), nodes)
+    }
+}
@@ -0,0 +1,30 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of physical time for hybrid logical clock IDs, see
+/// [`MovableTree::with_clock`](crate::MovableTree::with_clock). Clocks are
+/// owned by the tree, so they must be `Send` for the tree to be.
+pub trait Clock: Send {
+    /// The current physical time, in whatever unit the clock uses. All peers
+    /// of a document should use the same unit, and times must fit in 48 bits,
+    /// which milliseconds since the Unix epoch do for thousands of years.
+    fn now(&mut self) -> u64;
+}
+
+/// Wall-clock time in milliseconds since the Unix epoch.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+impl<F: FnMut() -> u64 + Send> Clock for F {
+    fn now(&mut self) -> u64 {
+        self()
+    }
+}
@@ -0,0 +1,411 @@
+//! Binary encoding of op logs.
+//!
+//! Every encoding starts with a one byte format version. Integers are LEB128
+//! varints, so the width of lamport counters or peers is not part of the
+//! format. When the layout has to change, bump [`VERSION`] and keep a decoder
+//! for every older version so that stored data can still be read and
+//! migrated.
+//!
+//! Version 2 layout:
+//!
+//! ```text
+//! version   u8
+//! peers     varint, followed by each peer's log:
+//!   peer    varint
+//!   len     varint, followed by `len` ops:
+//!     lamport  varint
+//!     kind     u8: 0 create, 1 move, 2 remove edge
+//!     target   node
+//!     parent   node
+//!     counter  varint (move only)
+//!     meta     u8: 0 none, 1 followed by author, timestamp and message
+//! node      as written by `NodeKey::encode`
+//! ```
+//!
+//! Version 1 is the same except that creates have no target, which is then
+//! derived from the op ID with [`NodeKey::from_op_id`].
+//!
+//! The edge state of an [`EvanTree`](crate::evan::EvanTree) has its own
+//! version, [`STATE_VERSION`]. Version 2 layout:
+//!
+//! ```text
+//! version   u8
+//! peers     varint, followed by the version vector sorted by peer:
+//!   peer    varint
+//!   lamport varint
+//! children  varint, followed by the edges of each child:
+//!   child   node
+//!   len     varint, followed by `len` edges:
+//!     parent   node
+//!     counter  varint: 0 for a removed edge, otherwise the counter plus 1
+//!     lamport  varint
+//!     peer     varint index into the version vector
+//! ```
+//!
+//! Version 1 has no removed edges and stores the counter as is.
+use std::{
+    fmt::{Display, Formatter},
+    sync::Arc,
+};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    evan::{EdgeCounter, VersionVector},
+    NodeKey, Op, OpMeta, TreeOp, ID, MAX_LAMPORT,
+};
+
+pub const VERSION: u8 = 2;
+pub const STATE_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    Invalid(&'static str),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported encoding version {}", v),
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode_ops<K: NodeKey>(ops: &FxHashMap<u64, Vec<Op<K>>>) -> Vec<u8> {
+    let mut buf = vec![VERSION];
+    let mut peers: Vec<_> = ops.keys().copied().collect();
+    peers.sort();
+    write_varint(&mut buf, peers.len() as u64);
+    for peer in peers {
+        let log = &ops[&peer];
+        write_varint(&mut buf, peer);
+        write_varint(&mut buf, log.len() as u64);
+        for op in log {
+            write_varint(&mut buf, op.id.lamport);
+            match op.op {
+                TreeOp::Create { target, parent } => {
+                    buf.push(0);
+                    target.encode(&mut buf);
+                    parent.encode(&mut buf);
+                }
+                TreeOp::Move {
+                    target,
+                    parent,
+                    counter,
+                } => {
+                    buf.push(1);
+                    target.encode(&mut buf);
+                    parent.encode(&mut buf);
+                    write_varint(&mut buf, counter as u64);
+                }
+                TreeOp::RemoveEdge { target, parent } => {
+                    buf.push(2);
+                    target.encode(&mut buf);
+                    parent.encode(&mut buf);
+                }
+            }
+            write_meta(&mut buf, op.meta.as_deref());
+        }
+    }
+    buf
+}
+
+pub fn decode_ops<K: NodeKey>(bytes: &[u8]) -> Result<FxHashMap<u64, Vec<Op<K>>>, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let version = reader.read_u8()?;
+    if !(1..=VERSION).contains(&version) {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let mut ans = FxHashMap::default();
+    for _ in 0..reader.read_varint()? {
+        let peer = reader.read_varint()?;
+        let len = reader.read_varint()?;
+        let mut log = Vec::with_capacity(len.min(bytes.len() as u64) as usize);
+        for _ in 0..len {
+            let id = ID {
+                lamport: reader.read_lamport()?,
+                peer,
+            };
+            let op = match reader.read_u8()? {
+                0 => TreeOp::Create {
+                    target: match version {
+                        1 => K::from_op_id(id).ok_or(DecodeError::Invalid("node key"))?,
+                        _ => K::decode(&mut reader)?,
+                    },
+                    parent: K::decode(&mut reader)?,
+                },
+                1 => TreeOp::Move {
+                    target: K::decode(&mut reader)?,
+                    parent: K::decode(&mut reader)?,
+                    counter: reader.read_u32()?,
+                },
+                2 => TreeOp::RemoveEdge {
+                    target: K::decode(&mut reader)?,
+                    parent: K::decode(&mut reader)?,
+                },
+                _ => return Err(DecodeError::Invalid("op kind")),
+            };
+            let meta = reader.read_meta()?.map(Arc::new);
+            log.push(Op { id, op, meta });
+        }
+        validate_log(&log)?;
+        ans.insert(peer, log);
+    }
+    if !reader.is_empty() {
+        return Err(DecodeError::Invalid("trailing bytes"));
+    }
+    Ok(ans)
+}
+
+/// Reject logs that no peer can have written, which the trees would
+/// otherwise trip over. Lamports of a peer must strictly increase, except
+/// for the moves an `EvanTree` emits for a single local move, which share
+/// one ID. The root can't be created or moved.
+fn validate_log<K: NodeKey>(log: &[Op<K>]) -> Result<(), DecodeError> {
+    for (i, op) in log.iter().enumerate() {
+        if op.op.target() == K::root() {
+            return Err(DecodeError::Invalid("op on the root"));
+        }
+        if let Some(prev) = i.checked_sub(1).map(|i| &log[i]) {
+            let same_move = prev.id == op.id
+                && matches!(prev.op, TreeOp::Move { .. })
+                && matches!(op.op, TreeOp::Move { .. });
+            if prev.id.lamport > op.id.lamport || (prev.id == op.id && !same_move) {
+                return Err(DecodeError::Invalid("lamport order"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The edges of an `EvanTree`, together with the version vector of the
+/// replica they were taken from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EdgeState<K> {
+    pub version: VersionVector,
+    pub edges: Vec<EdgeEntry<K>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeEntry<K> {
+    pub child: K,
+    pub parent: K,
+    pub value: EdgeCounter,
+    /// A removed edge only carries the op ID that removed it.
+    pub removed: bool,
+}
+
+pub fn encode_state<K: NodeKey>(state: &EdgeState<K>) -> Vec<u8> {
+    let mut buf = vec![STATE_VERSION];
+    let mut peers: Vec<_> = state.version.iter().map(|(&p, &l)| (p, l)).collect();
+    peers.sort();
+    write_varint(&mut buf, peers.len() as u64);
+    for &(peer, lamport) in &peers {
+        write_varint(&mut buf, peer);
+        write_varint(&mut buf, lamport);
+    }
+    let mut edges = state.edges.clone();
+    edges.sort_by_key(|e| (e.child, e.parent));
+    let children: Vec<_> = edges.chunk_by(|a, b| a.child == b.child).collect();
+    write_varint(&mut buf, children.len() as u64);
+    for edges in children {
+        edges[0].child.encode(&mut buf);
+        write_varint(&mut buf, edges.len() as u64);
+        for edge in edges {
+            edge.parent.encode(&mut buf);
+            match edge.removed {
+                true => write_varint(&mut buf, 0),
+                false => write_varint(&mut buf, edge.value.counter as u64 + 1),
+            }
+            write_varint(&mut buf, edge.value.lamport);
+            let peer = peers.binary_search_by_key(&edge.value.peer, |&(p, _)| p);
+            write_varint(
+                &mut buf,
+                peer.expect("edge peer missing from version vector") as u64,
+            );
+        }
+    }
+    buf
+}
+
+pub fn decode_state<K: NodeKey>(bytes: &[u8]) -> Result<EdgeState<K>, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let version = reader.read_u8()?;
+    if !(1..=STATE_VERSION).contains(&version) {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let mut peers = Vec::new();
+    for _ in 0..reader.read_varint()? {
+        peers.push((reader.read_varint()?, reader.read_lamport()?));
+    }
+    let mut edges = Vec::new();
+    for _ in 0..reader.read_varint()? {
+        let child = K::decode(&mut reader)?;
+        for _ in 0..reader.read_varint()? {
+            let parent = K::decode(&mut reader)?;
+            let (counter, removed) = match (version, reader.read_varint()?) {
+                (1, counter) => (counter, false),
+                (_, 0) => (0, true),
+                (_, counter) => (counter - 1, false),
+            };
+            let counter = u32::try_from(counter).map_err(|_| DecodeError::Invalid("counter"))?;
+            let lamport = reader.read_lamport()?;
+            let peer = usize::try_from(reader.read_varint()?)
+                .ok()
+                .and_then(|i| peers.get(i))
+                .ok_or(DecodeError::Invalid("peer index"))?
+                .0;
+            edges.push(EdgeEntry {
+                child,
+                parent,
+                value: EdgeCounter {
+                    counter,
+                    lamport,
+                    peer,
+                },
+                removed,
+            });
+        }
+    }
+    if !reader.is_empty() {
+        return Err(DecodeError::Invalid("trailing bytes"));
+    }
+    Ok(EdgeState {
+        version: peers.into_iter().collect(),
+        edges,
+    })
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_meta(buf: &mut Vec<u8>, meta: Option<&OpMeta>) {
+    let Some(meta) = meta else {
+        buf.push(0);
+        return;
+    };
+    buf.push(1);
+    match &meta.author {
+        Some(author) => {
+            buf.push(1);
+            write_bytes(buf, author.as_bytes());
+        }
+        None => buf.push(0),
+    }
+    match meta.timestamp {
+        Some(timestamp) => {
+            buf.push(1);
+            write_varint(buf, timestamp);
+        }
+        None => buf.push(0),
+    }
+    write_bytes(buf, &meta.message);
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 || shift > 63 {
+                return Err(DecodeError::Invalid("varint"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        u32::try_from(self.read_varint()?).map_err(|_| DecodeError::Invalid("counter"))
+    }
+
+    pub fn read_lamport(&mut self) -> Result<u64, DecodeError> {
+        Some(self.read_varint()?)
+            .filter(|&lamport| lamport <= MAX_LAMPORT)
+            .ok_or(DecodeError::Invalid("lamport"))
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.bytes.len() < N {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.read_varint()?;
+        if len > self.bytes.len() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.bytes.split_at(len as usize);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn read_meta(&mut self) -> Result<Option<OpMeta>, DecodeError> {
+        if self.read_u8()? == 0 {
+            return Ok(None);
+        }
+        let author = match self.read_u8()? {
+            0 => None,
+            _ => Some(
+                String::from_utf8(self.read_bytes()?.to_vec())
+                    .map_err(|_| DecodeError::Invalid("author"))?,
+            ),
+        };
+        let timestamp = match self.read_u8()? {
+            0 => None,
+            _ => Some(self.read_varint()?),
+        };
+        let message = self.read_bytes()?.to_vec();
+        Ok(Some(OpMeta {
+            author,
+            timestamp,
+            message,
+        }))
+    }
+}
@@ -1,99 +1,329 @@
 use fxhash::{FxHashMap, FxHashSet};
-use std::collections::{hash_map::Entry, BinaryHeap};
+use std::{cmp::Ordering, collections::BinaryHeap};
 
-use crate::{MovableTreeAlgorithm, NodeID, Op, TreeNode, TreeOp, ROOT_ID};
+use crate::{
+    arena::{Arena, NIL},
+    encoding::{self, DecodeError, EdgeEntry, EdgeState},
+    MergeReport, MovableTreeAlgorithm, NodeID, NodeKey, Op, ParentCause, TreeNode, TreeOp,
+};
 
-#[derive(Debug, Clone, Copy)]
-struct EdgeCounter {
-    counter: u32,
-    lamport: u32,
-    peer: u64,
+/// The last-writer-wins value of an edge from a child to a parent. The edge
+/// with the largest `counter` is the most recent one, while `lamport` and
+/// `peer` order concurrent writes to the same edge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdgeCounter {
+    pub counter: u32,
+    pub lamport: u64,
+    pub peer: u64,
 }
 
 #[derive(Debug, Clone)]
-pub struct Node {
-    id: NodeID,
-    parent: Option<NodeID>,
-    edges: FxHashMap<NodeID, EdgeCounter>,
+struct NodeData {
+    parent: u32,
+    /// The parent of the winning edge, kept up to date as edges are written
+    /// so that it doesn't take a scan of all edges to find it.
+    largest_edge: u32,
+    /// A node rarely has more than a few edges, so a `Vec` is both smaller
+    /// and faster than a map.
+    edges: Vec<Edge>,
 }
 
-impl Node {
-    pub fn largest_edge(&self) -> Option<NodeID> {
-        self.edges
-            .iter()
-            .max_by(
-                |(a_id, EdgeCounter { counter: a_c, .. }),
-                 (b_id, EdgeCounter { counter: b_c, .. })| {
-                    a_c.cmp(b_c).then_with(|| a_id.cmp(b_id))
-                },
-            )
-            .map(|(id, _)| *id)
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    parent: u32,
+    value: EdgeCounter,
+    /// Set by a remove-edge op. The edge is kept as a tombstone so that the
+    /// removal still wins over concurrent writes with smaller op IDs.
+    removed: bool,
+}
+
+impl NodeData {
+    fn entry(&self, parent: u32) -> Option<&Edge> {
+        self.edges.iter().find(|e| e.parent == parent)
+    }
+
+    /// The edge to `parent`, unless it has been removed.
+    fn edge(&self, parent: u32) -> Option<&EdgeCounter> {
+        self.entry(parent).filter(|e| !e.removed).map(|e| &e.value)
+    }
+
+    fn live_edges(&self) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(|e| !e.removed)
+    }
+
+    /// Returns the previous value of the edge.
+    fn set_edge(&mut self, edge: Edge) -> Option<Edge> {
+        match self.edges.iter_mut().find(|e| e.parent == edge.parent) {
+            Some(old) => Some(std::mem::replace(old, edge)),
+            None => {
+                // Most nodes only ever get a handful of edges, so don't let
+                // the `Vec` grow to its default minimum capacity of four.
+                self.edges.reserve_exact(1);
+                self.edges.push(edge);
+                None
+            }
+        }
     }
 }
 
-pub struct EvanTree {
-    pub nodes: FxHashMap<NodeID, Node>,
+/// A node of an [`EvanTree`], see [`EvanTree::get_node`].
+#[derive(Clone, Copy)]
+pub struct Node<'a, K = NodeID> {
+    tree: &'a EvanTree<K>,
+    idx: u32,
+}
+
+impl<K: NodeKey> Node<'_, K> {
+    pub fn id(&self) -> K {
+        self.tree.arena.key(self.idx)
+    }
+
+    pub fn parent(&self) -> Option<K> {
+        Some(self.tree.arena.key(self.tree.parent_of(self.idx)?))
+    }
+
+    /// The parent of the most recent edge, which is the parent unless the
+    /// node was rescued from a cycle.
+    pub fn largest_edge(&self) -> Option<K> {
+        Some(self.tree.arena.key(self.tree.largest_edge(self.idx)?))
+    }
+
+    /// The edges that have not been removed.
+    pub fn edges(&self) -> Vec<(K, EdgeCounter)> {
+        self.tree.edges(self.id())
+    }
+}
+
+impl<K: NodeKey> std::fmt::Debug for Node<'_, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id())
+            .field("parent", &self.parent())
+            .field("edges", &self.edges())
+            .finish()
+    }
+}
+
+/// The largest lamport of each peer whose edge writes a replica has seen.
+/// A replica has seen every write of `peer` up to `version[peer]`.
+pub type VersionVector = FxHashMap<u64, u64>;
+
+#[derive(Clone)]
+pub struct EvanTree<K = NodeID> {
+    arena: Arena<K>,
+    /// Indexed by arena index. Nodes that are only known as the parent of an
+    /// edge have no edges themselves.
+    nodes: Vec<NodeData>,
+    version: VersionVector,
+    /// Break ties between edges like `js/index.js`, see
+    /// [`EvanTree::use_js_ties`].
+    js_ties: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct PQItem {
-    child: NodeID,
-    parent: NodeID,
+struct PQItem<K> {
+    child: K,
+    parent: K,
     counter: u32,
+    /// The lamport and peer of the op that wrote the edge
+    op: (u64, u64),
+    js_ties: bool,
 }
 
-impl PartialOrd for PQItem {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+impl<K: NodeKey> PartialOrd for PQItem<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for PQItem {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.counter
-            .cmp(&other.counter)
-            .then_with(|| self.parent.cmp(&other.parent))
-            .then_with(|| self.child.cmp(&other.child))
+impl<K: NodeKey> Ord for PQItem<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let js = self.js_ties;
+        let ties = cmp_keys(self.parent, other.parent, js)
+            .then_with(|| cmp_keys(self.child, other.child, js));
+        // The heap pops the largest item, so ties go to the later create,
+        // as in `EvanTree::cmp_edges`, then to the largest parent and then the
+        // largest child. The priority queue in the JS reference only looks at
+        // the keys and pops the smallest ones.
+        let ties = match (self.counter, js) {
+            (_, true) => ties.reverse(),
+            (0, false) => self.op.cmp(&other.op).then(ties),
+            _ => ties,
+        };
+        self.counter.cmp(&other.counter).then(ties)
+    }
+}
+
+/// Order node keys, or with `js` order them like `js/index.js` compares node
+/// IDs, where the root's ID `(ROOT)` sorts before the ID of any other node.
+fn cmp_keys<K: NodeKey>(a: K, b: K, js: bool) -> Ordering {
+    if js {
+        (a != K::root())
+            .cmp(&(b != K::root()))
+            .then_with(|| a.cmp(&b))
+    } else {
+        a.cmp(&b)
     }
 }
 
-impl Default for EvanTree {
+impl<K: NodeKey> Default for EvanTree<K> {
     fn default() -> Self {
-        let root = Node {
-            id: ROOT_ID,
-            parent: None,
-            edges: FxHashMap::default(),
+        let root = NodeData {
+            parent: NIL,
+            largest_edge: NIL,
+            edges: Vec::new(),
         };
-        let mut nodes = FxHashMap::default();
-        nodes.insert(root.id, root);
-        EvanTree { nodes }
+        EvanTree {
+            arena: Arena::new(),
+            nodes: vec![root],
+            version: VersionVector::default(),
+            js_ties: false,
+        }
     }
 }
 
-impl EvanTree {
+impl<K: NodeKey> EvanTree<K> {
     pub fn new() -> Self {
         Self::default()
     }
 
-    fn recompute_parent_children(&mut self) {
+    fn intern(&mut self, key: K) -> u32 {
+        let (idx, new) = self.arena.intern(key);
+        if new {
+            self.nodes.push(NodeData {
+                parent: NIL,
+                largest_edge: NIL,
+                edges: Vec::new(),
+            });
+        }
+        idx
+    }
+
+    fn node(&self, idx: u32) -> &NodeData {
+        &self.nodes[idx as usize]
+    }
+
+    fn node_mut(&mut self, idx: u32) -> &mut NodeData {
+        &mut self.nodes[idx as usize]
+    }
+
+    fn parent_of(&self, idx: u32) -> Option<u32> {
+        let parent = self.node(idx).parent;
+        (parent != NIL).then_some(parent)
+    }
+
+    fn largest_edge(&self, idx: u32) -> Option<u32> {
+        let largest = self.node(idx).largest_edge;
+        debug_assert_eq!(largest, self.find_largest_edge(idx));
+        (largest != NIL).then_some(largest)
+    }
+
+    fn find_largest_edge(&self, idx: u32) -> u32 {
+        self.node(idx)
+            .live_edges()
+            .max_by(|a, b| self.cmp_edges((a.parent, a.value), (b.parent, b.value)))
+            .map_or(NIL, |e| e.parent)
+    }
+
+    /// Order edges by counter, breaking ties by the key of the parent. Create
+    /// edges all have counter 0, and ties between them go to the later op
+    /// first, so that a concurrent create of an existing node acts like a
+    /// move, as it does in Kleppmann's algorithm.
+    fn cmp_edges(
+        &self,
+        (a, a_value): (u32, EdgeCounter),
+        (b, b_value): (u32, EdgeCounter),
+    ) -> Ordering {
+        a_value
+            .counter
+            .cmp(&b_value.counter)
+            .then_with(|| match a_value.counter {
+                0 => (a_value.lamport, a_value.peer).cmp(&(b_value.lamport, b_value.peer)),
+                _ => Ordering::Equal,
+            })
+            .then_with(|| cmp_keys(self.arena.key(a), self.arena.key(b), self.js_ties))
+    }
+
+    /// Write the edge from `idx` to `parent`. A remote edge only replaces an
+    /// existing value written by a smaller op ID.
+    fn write_edge(&mut self, idx: u32, parent: u32, edge: EdgeCounter, remote: bool) {
+        self.write_entry(
+            idx,
+            Edge {
+                parent,
+                value: edge,
+                removed: false,
+            },
+            remote,
+        );
+    }
+
+    fn write_entry(&mut self, idx: u32, entry: Edge, remote: bool) {
+        let Edge {
+            parent,
+            value: edge,
+            removed,
+        } = entry;
+        let seen = self.version.entry(edge.peer).or_insert(edge.lamport);
+        *seen = (*seen).max(edge.lamport);
+        let node = self.node_mut(idx);
+        if remote
+            && node
+                .entry(parent)
+                .is_some_and(|old| (old.value.lamport, old.value.peer) >= (edge.lamport, edge.peer))
+        {
+            return;
+        }
+        let old = node.set_edge(entry).filter(|old| !old.removed);
+        let largest = node.largest_edge;
+        let largest_value = node.edge(largest).copied();
+        self.node_mut(idx).largest_edge = if removed {
+            if largest == parent {
+                self.find_largest_edge(idx)
+            } else {
+                largest
+            }
+        } else if largest == NIL {
+            parent
+        } else if largest == parent {
+            if old.is_some_and(|old| {
+                self.cmp_edges((parent, old.value), (parent, edge)) == Ordering::Greater
+            }) {
+                self.find_largest_edge(idx)
+            } else {
+                parent
+            }
+        } else {
+            match self.cmp_edges((parent, edge), (largest, largest_value.unwrap())) {
+                Ordering::Greater => parent,
+                _ => largest,
+            }
+        };
+    }
+
+    /// Returns the nodes that had to be reattached away from their most recent
+    /// edge to break a cycle, together with the parent they ended up under.
+    fn recompute_parent_children(&mut self) -> Vec<(K, K)> {
         // Start off with all children arrays empty and each parent pointer
         // for a given node set to the most recent edge for that node.
-        self.nodes.values_mut().for_each(|node| {
-            node.parent = node.largest_edge();
-            // node.children.clear();
-        });
+        for idx in 0..self.nodes.len() as u32 {
+            self.node_mut(idx).parent = self.largest_edge(idx).unwrap_or(NIL);
+        }
         // At this point all nodes that can reach the root form a tree (by
         // construction, since each node other than the root has a single
         // parent). The parent pointers for the remaining nodes may form one
         // or more cycles. Gather all remaining nodes detached from the root.
-        let mut non_rooted_nodes = FxHashSet::default();
-        for node in self.nodes.values() {
-            if !non_rooted_nodes.contains(&node.id) && !self.is_under_other(node.id, ROOT_ID) {
-                let mut node_id = Some(node.id);
+        let mut non_rooted = vec![false; self.nodes.len()];
+        let mut non_rooted_nodes = Vec::new();
+        let mut rescued = Vec::new();
+        for idx in 0..self.nodes.len() as u32 {
+            if !non_rooted[idx as usize] && !self.is_under(idx, Arena::<K>::ROOT) {
+                let mut node_id = Some(idx);
                 while let Some(node) = node_id {
-                    if !non_rooted_nodes.contains(&node) {
-                        non_rooted_nodes.insert(node);
-                        node_id = self.parent(node);
+                    if !non_rooted[node as usize] {
+                        non_rooted[node as usize] = true;
+                        non_rooted_nodes.push(node);
+                        node_id = self.parent_of(node);
                     } else {
                         break;
                     }
@@ -111,34 +341,34 @@ impl EvanTree {
             let mut deferred_edges = FxHashMap::default();
             let mut ready_edges = BinaryHeap::new();
             for &child in non_rooted_nodes.iter() {
-                for (&parent, &counter) in self.nodes.get(&child).unwrap().edges.iter() {
-                    if !non_rooted_nodes.contains(&parent) {
-                        ready_edges.push(PQItem {
-                            child,
-                            parent,
-                            counter: counter.counter,
-                        });
+                for &Edge { parent, value, .. } in self.node(child).live_edges() {
+                    let item = PQItem {
+                        child: self.arena.key(child),
+                        parent: self.arena.key(parent),
+                        counter: value.counter,
+                        op: (value.lamport, value.peer),
+                        js_ties: self.js_ties,
+                    };
+                    if !non_rooted[parent as usize] {
+                        ready_edges.push(item);
                     } else {
                         deferred_edges
                             .entry(parent)
                             .or_insert_with(Vec::new)
-                            .push(PQItem {
-                                child,
-                                parent,
-                                counter: counter.counter,
-                            });
+                            .push(item);
                     }
                 }
             }
             while let Some(top) = ready_edges.pop() {
-                let child = top.child;
-                if !non_rooted_nodes.contains(&child) {
+                let child = self.arena.get(&top.child).unwrap();
+                if !non_rooted[child as usize] {
                     continue;
                 }
 
                 // reattach child to parent
-                self.nodes.get_mut(&child).unwrap().parent = Some(top.parent);
-                non_rooted_nodes.remove(&child);
+                self.node_mut(child).parent = self.arena.get(&top.parent).unwrap();
+                non_rooted[child as usize] = false;
+                rescued.push((top.child, top.parent));
 
                 // active all deferred edges for child
                 if let Some(deferred) = deferred_edges.remove(&child) {
@@ -147,71 +377,253 @@ impl EvanTree {
                     }
                 }
             }
+            // What is left has no live edge leading back to the root, which
+            // only happens once edges have been removed. Detach it, as its
+            // parent pointers may still form a cycle.
+            for &idx in non_rooted_nodes.iter() {
+                if non_rooted[idx as usize] {
+                    self.node_mut(idx).parent = NIL;
+                }
+            }
+        }
+        // Nodes that were reattached to the parent they pointed to anyway
+        // were only carried along with a rescued ancestor.
+        rescued.retain(|(child, parent)| {
+            let child = self.arena.get(child).unwrap();
+            self.largest_edge(child) != self.arena.get(parent)
+        });
+        rescued
+    }
+
+    /// The nodes that are not under the parent of their most recent edge,
+    /// together with the parent they are under instead.
+    fn rescues(&self) -> FxHashSet<(u32, u32)> {
+        (0..self.nodes.len() as u32)
+            .filter_map(|idx| {
+                let parent = self.parent_of(idx)?;
+                (self.largest_edge(idx) != Some(parent)).then_some((idx, parent))
+            })
+            .collect()
+    }
+
+    /// Recompute the parents and return the rescues that were not in effect
+    /// yet in `before`, so that a node that stays rescued under the same
+    /// parent is only reported by the merge that rescued it.
+    fn recompute_new_rescues(&mut self, before: FxHashSet<(u32, u32)>) -> Vec<(K, K)> {
+        let mut rescued = self.recompute_parent_children();
+        rescued.retain(|(child, parent)| {
+            let pair = (
+                self.arena.get(child).unwrap(),
+                self.arena.get(parent).unwrap(),
+            );
+            !before.contains(&pair)
+        });
+        rescued
+    }
+
+    /// Switch the tie-breaks and recompute the winning edges and parents.
+    #[cfg(feature = "p2pdb")]
+    pub(crate) fn set_js_ties(&mut self, js_ties: bool) {
+        self.js_ties = js_ties;
+        for idx in 0..self.nodes.len() as u32 {
+            self.node_mut(idx).largest_edge = self.find_largest_edge(idx);
+        }
+        self.recompute_parent_children();
+    }
+
+    /// Nodes only known as the parent of an edge are not in the tree yet.
+    fn has_node(&self, idx: u32) -> bool {
+        idx == Arena::<K>::ROOT || !self.node(idx).edges.is_empty()
+    }
+
+    pub fn get_node(&self, id: K) -> Option<Node<'_, K>> {
+        let idx = self.arena.get(&id)?;
+        self.has_node(idx).then_some(Node { tree: self, idx })
+    }
+
+    /// Every node in the tree, including the root.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = Node<'_, K>> {
+        (0..self.nodes.len() as u32)
+            .filter(|&idx| self.has_node(idx))
+            .map(|idx| Node { tree: self, idx })
+    }
+
+    /// The edges of `node` that have not been removed.
+    pub fn edges(&self, node: K) -> Vec<(K, EdgeCounter)> {
+        let Some(idx) = self.arena.get(&node) else {
+            return Vec::new();
+        };
+        self.node(idx)
+            .live_edges()
+            .map(|e| (self.arena.key(e.parent), e.value))
+            .collect()
+    }
+
+    pub fn version(&self) -> &VersionVector {
+        &self.version
+    }
+
+    /// Merge the edges of another replica. The edges are last-writer-wins
+    /// registers, so this converges to the same tree as merging the ops that
+    /// wrote them, without needing any op log.
+    pub fn merge_state(&mut self, other: &EvanTree<K>) -> MergeReport<K> {
+        self.merge_edges(EdgeState {
+            version: other.version.clone(),
+            edges: other.edges_since(&VersionVector::default()),
+        })
+    }
+
+    /// Encode every edge. See [`encoding`] for the layout.
+    pub fn export_state(&self) -> Vec<u8> {
+        self.export_delta(&VersionVector::default())
+    }
+
+    /// Encode only the edges written after `since`, the version vector of the
+    /// replica that is going to import them.
+    pub fn export_delta(&self, since: &VersionVector) -> Vec<u8> {
+        encoding::encode_state(&EdgeState {
+            version: self.version.clone(),
+            edges: self.edges_since(since),
+        })
+    }
+
+    pub fn import_state(&mut self, bytes: &[u8]) -> Result<MergeReport<K>, DecodeError> {
+        let state = encoding::decode_state(bytes)?;
+        if state.edges.iter().any(|e| e.child == K::root()) {
+            return Err(DecodeError::Invalid("edge of the root"));
+        }
+        Ok(self.merge_edges(state))
+    }
+
+    pub(crate) fn edges_since(&self, since: &VersionVector) -> Vec<EdgeEntry<K>> {
+        let mut edges = Vec::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            for edge in &node.edges {
+                let Edge {
+                    parent,
+                    value,
+                    removed,
+                } = *edge;
+                if since
+                    .get(&value.peer)
+                    .is_none_or(|&seen| value.lamport > seen)
+                {
+                    edges.push(EdgeEntry {
+                        child: self.arena.key(idx as u32),
+                        parent: self.arena.key(parent),
+                        value,
+                        removed,
+                    });
+                }
+            }
         }
+        edges
     }
 
-    pub fn is_under_other(&self, node: NodeID, other: NodeID) -> bool {
+    pub(crate) fn merge_edges(&mut self, state: EdgeState<K>) -> MergeReport<K> {
+        let before = self.rescues();
+        for edge in state.edges {
+            let child = self.intern(edge.child);
+            let parent = self.intern(edge.parent);
+            self.write_entry(
+                child,
+                Edge {
+                    parent,
+                    value: edge.value,
+                    removed: edge.removed,
+                },
+                true,
+            );
+        }
+        for (peer, lamport) in state.version {
+            let seen = self.version.entry(peer).or_insert(lamport);
+            *seen = (*seen).max(lamport);
+        }
+        MergeReport {
+            rescued: self.recompute_new_rescues(before),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_under_other(&self, node: K, other: K) -> bool {
+        if node == other {
+            return true;
+        }
+        match (self.arena.get(&node), self.arena.get(&other)) {
+            (Some(node), Some(other)) => self.is_under(node, other),
+            _ => false,
+        }
+    }
+
+    fn is_under(&self, node: u32, other: u32) -> bool {
         if node == other {
             return true;
         }
         let mut tortoise = node;
-        let mut hare = self.parent(node);
+        let mut hare = self.parent_of(node);
         while hare.is_some() && hare.unwrap() != other {
             if tortoise == hare.unwrap() {
                 return false;
             }
-            hare = self.parent(hare.unwrap());
+            hare = self.parent_of(hare.unwrap());
             if hare.is_none() || hare.unwrap() == other {
                 break;
             }
-            tortoise = self.parent(tortoise).unwrap();
-            hare = self.parent(hare.unwrap());
+            tortoise = self.parent_of(tortoise).unwrap();
+            hare = self.parent_of(hare.unwrap());
         }
         hare == Some(other)
     }
 
-    fn ensure_node_is_rooted(
-        &mut self,
-        mut node: Option<NodeID>,
-        edits: &mut Vec<(NodeID, NodeID)>,
-    ) {
-        while let Some(child) = node.and_then(|id| self.nodes.get(&id)) {
-            let parent = child.parent;
+    fn ensure_node_is_rooted(&mut self, mut node: Option<u32>, edits: &mut Vec<(u32, u32)>) {
+        while let Some(child) = node {
+            let parent = self.parent_of(child);
             if parent.is_none() {
                 break;
             }
-            let edge = child.largest_edge();
-            if edge != parent {
-                edits.push((node.unwrap(), parent.unwrap()));
+            let edge = self.largest_edge(child);
+            // The chains above the old and the new parent may share nodes,
+            // and every edit of one op has to carry its own edge.
+            if edge != parent && !edits.contains(&(child, parent.unwrap())) {
+                edits.push((child, parent.unwrap()));
             }
             node = parent;
         }
     }
 }
 
-impl MovableTreeAlgorithm for EvanTree {
+impl<K: NodeKey> MovableTreeAlgorithm<K> for EvanTree<K> {
     fn new() -> Self {
         Self::new()
     }
 
-    fn apply(&mut self, op: Op, local: bool) -> Vec<Op> {
+    fn apply(&mut self, op: Op<K>, local: bool) -> Vec<Op<K>> {
         let id = op.id;
         match op.op {
-            TreeOp::Create { parent } => {
-                let child = self.nodes.entry(id.into()).or_insert_with(|| Node {
-                    id: id.into(),
-                    parent: Some(parent),
-                    // children: vec![],
-                    edges: FxHashMap::default(),
-                });
-                child.edges.insert(
+            TreeOp::Create { target, parent } => {
+                let target = self.intern(target);
+                let parent = self.intern(parent);
+                let child = self.node_mut(target);
+                let existed = !child.edges.is_empty();
+                if !existed {
+                    child.parent = parent;
+                }
+                // A node created more than once by concurrent creates of the
+                // same key gets one edge per create, which compete like the
+                // edges of concurrent moves.
+                self.write_edge(
+                    target,
                     parent,
                     EdgeCounter {
                         counter: 0,
                         lamport: id.lamport,
                         peer: id.peer,
                     },
+                    true,
                 );
+                if existed && local {
+                    self.recompute_parent_children();
+                }
                 vec![op]
             }
             TreeOp::Move {
@@ -219,88 +631,145 @@ impl MovableTreeAlgorithm for EvanTree {
                 parent,
                 counter,
             } => {
+                // Remote ops of different peers arrive in no particular order,
+                // so a move can show up before the create of its target.
+                let child = self.intern(target);
+                let parent = self.intern(parent);
                 if local {
-                    let child = target;
                     let mut edits = vec![];
-                    let old_parent = self.parent(child);
+                    let old_parent = self.parent_of(child);
                     self.ensure_node_is_rooted(old_parent, &mut edits);
                     self.ensure_node_is_rooted(Some(parent), &mut edits);
                     edits.push((child, parent));
                     let mut ans = Vec::with_capacity(edits.len());
                     for (child, parent) in edits {
                         let max_counter = self
-                            .nodes
-                            .get(&child)
-                            .unwrap()
-                            .edges
-                            .values()
-                            .map(|c| c.counter as i64)
+                            .node(child)
+                            .live_edges()
+                            .map(|e| e.value.counter as i64)
                             .max()
                             .unwrap_or(-1);
-                        self.nodes.get_mut(&child).unwrap().edges.insert(
+                        self.write_edge(
+                            child,
                             parent,
                             EdgeCounter {
                                 counter: (max_counter + 1) as u32,
                                 lamport: id.lamport,
                                 peer: id.peer,
                             },
+                            false,
                         );
+                        // Every edit carries the node whose edge it writes,
+                        // not the moved node, or remote peers would write the
+                        // rooting edits onto the moved node.
                         ans.push(Op {
                             id,
                             op: TreeOp::Move {
-                                target,
-                                parent,
+                                target: self.arena.key(child),
+                                parent: self.arena.key(parent),
                                 counter: (max_counter + 1) as u32,
                             },
+                            meta: op.meta.clone(),
                         })
                     }
                     self.recompute_parent_children();
                     ans
                 } else {
-                    let child = target;
-                    let edge = self.nodes.get_mut(&child).unwrap().edges.entry(parent);
-                    match edge {
-                        Entry::Occupied(mut entry) => {
-                            let old_counter = entry.get_mut();
-                            if old_counter.lamport < id.lamport
-                                || (old_counter.lamport == id.lamport && old_counter.peer < id.peer)
-                            {
-                                old_counter.counter = counter;
-                                old_counter.lamport = id.lamport;
-                                old_counter.peer = id.peer;
-                            }
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert(EdgeCounter {
-                                counter,
-                                lamport: id.lamport,
-                                peer: id.peer,
-                            });
-                        }
-                    }
+                    self.write_edge(
+                        child,
+                        parent,
+                        EdgeCounter {
+                            counter,
+                            lamport: id.lamport,
+                            peer: id.peer,
+                        },
+                        true,
+                    );
                     vec![]
                 }
             }
+            TreeOp::RemoveEdge { target, parent } => {
+                let child = self.intern(target);
+                let parent = self.intern(parent);
+                self.write_entry(
+                    child,
+                    Edge {
+                        parent,
+                        value: EdgeCounter {
+                            counter: 0,
+                            lamport: id.lamport,
+                            peer: id.peer,
+                        },
+                        removed: true,
+                    },
+                    true,
+                );
+                if local {
+                    self.recompute_parent_children();
+                }
+                vec![op]
+            }
         }
     }
 
-    fn merge(&mut self, ops: Vec<Op>) {
+    fn merge(&mut self, ops: Vec<Op<K>>) -> MergeReport<K> {
+        let before = self.rescues();
         for op in ops {
             self.apply(op, false);
         }
-        self.recompute_parent_children()
+        MergeReport {
+            rescued: self.recompute_new_rescues(before),
+            ..Default::default()
+        }
     }
 
-    fn nodes(&self) -> Vec<NodeID> {
-        self.nodes.keys().copied().collect()
+    fn nodes(&self) -> Vec<K> {
+        self.iter_nodes().map(|node| node.id()).collect()
+    }
+
+    fn contains(&self, node: K) -> bool {
+        self.get_node(node).is_some()
+    }
+
+    fn parent(&self, node: K) -> Option<K> {
+        let parent = self.parent_of(self.arena.get(&node)?)?;
+        Some(self.arena.key(parent))
+    }
+
+    fn is_effective(&self, op: &Op<K>) -> bool {
+        let (counter, removed) = match op.op {
+            TreeOp::Create { .. } => (0, false),
+            TreeOp::Move { counter, .. } => (counter, false),
+            TreeOp::RemoveEdge { .. } => (0, true),
+        };
+        let (Some(child), Some(parent)) = (
+            self.arena.get(&op.op.target()),
+            self.arena.get(&op.op.parent()),
+        ) else {
+            return false;
+        };
+        self.node(child).entry(parent).is_some_and(|edge| {
+            edge.removed == removed
+                && edge.value
+                    == EdgeCounter {
+                        counter,
+                        lamport: op.id.lamport,
+                        peer: op.id.peer,
+                    }
+        })
     }
 
-    fn parent(&self, node: NodeID) -> Option<NodeID> {
-        self.nodes.get(&node).and_then(|n| n.parent)
+    fn explain_parent(&self, node: K) -> Option<ParentCause<K>> {
+        let idx = self.arena.get(&node)?;
+        let parent = self.parent_of(idx)?;
+        Some(ParentCause::Edge {
+            parent: self.arena.key(parent),
+            counter: *self.node(idx).edge(parent).unwrap(),
+            rescued: self.largest_edge(idx) != Some(parent),
+        })
     }
 
-    fn get_root(&self) -> TreeNode {
-        let state = self.nodes.iter().map(|(&k, v)| (k, v.parent)).collect();
-        TreeNode::from_state(&state)
+    fn get_root(&self) -> TreeNode<K> {
+        self.arena.tree_node(|idx| self.parent_of(idx))
     }
 }
@@ -1,4 +1,14 @@
-use crate::{array_mut_ref, evan::EvanTree, martin::MartinTree, MovableTree};
+use std::{
+    cell::Cell,
+    fmt::Debug,
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+};
+
+use crate::{
+    array_mut_ref, evan::EvanTree, martin::MartinTree, model::check_invariants, MovableTree,
+    MovableTreeAlgorithm, NodeID, ROOT_ID,
+};
 use arbitrary::Arbitrary;
 use enum_as_inner::EnumAsInner;
 
@@ -108,9 +118,14 @@ struct Actor {
 
 impl Actor {
     fn new(peer: u64) -> Self {
+        let mut martin_tree = MovableTree::<MartinTree>::new(peer);
+        // check the ancestor index against the parents on half of the peers
+        if peer % 2 == 1 {
+            martin_tree.algorithm.enable_ancestor_index();
+        }
         Actor {
             peer,
-            martin_tree: MovableTree::new(peer),
+            martin_tree,
             evan_tree: MovableTree::new(peer),
         }
     }
@@ -178,16 +193,321 @@ impl Actor {
     }
 }
 
+/// Concurrent edits that are likely to form cycles once they meet. The
+/// moves of a pattern are made by different peers without syncing in
+/// between, and every move a peer would reject as a cycle is left out. Nodes
+/// are picked by index among the nodes the first peer knows.
+#[derive(Debug, Clone, Copy, Arbitrary)]
+pub enum Pattern {
+    /// `site` creates a node under `parent`, or under the root.
+    Create {
+        site: u8,
+        parent: u8,
+    },
+    /// One peer moves `a` under `b` while the next moves `b` under `a`.
+    Swap {
+        site: u8,
+        a: u8,
+        b: u8,
+    },
+    /// Three peers move `a` under `b`, `b` under `c` and `c` under `a`.
+    Rotate {
+        site: u8,
+        a: u8,
+        b: u8,
+        c: u8,
+    },
+    /// One peer moves the topmost ancestor of `node` under `other` while the
+    /// next moves `other` under `node`.
+    MoveAncestor {
+        site: u8,
+        node: u8,
+        other: u8,
+    },
+    /// `to` merges everything `from` has.
+    Sync {
+        from: u8,
+        to: u8,
+    },
+    SyncAll,
+}
+
+struct CycleFuzzer {
+    actors: Vec<Actor>,
+}
+
+impl CycleFuzzer {
+    fn new(site: usize) -> Self {
+        CycleFuzzer {
+            actors: (0..site as u64).map(Actor::new).collect(),
+        }
+    }
+
+    fn peer(&self, site: u8, offset: usize) -> usize {
+        (site as usize + offset) % self.actors.len()
+    }
+
+    fn node(&self, peer: usize, idx: u8) -> Option<NodeID> {
+        let mut nodes = self.actors[peer].martin_tree.nodes();
+        nodes.sort();
+        (!nodes.is_empty()).then(|| nodes[idx as usize % nodes.len()])
+    }
+
+    /// Move `target` under `parent` if `peer` knows both and it isn't a cycle
+    /// there.
+    fn mov(&mut self, peer: usize, target: NodeID, parent: NodeID) {
+        let actor = &mut self.actors[peer];
+        let known = actor.martin_tree.nodes();
+        if !known.contains(&target)
+            || !known.contains(&parent)
+            || actor.martin_tree.is_ancestor_of(target, parent)
+            || actor.evan_tree.is_ancestor_of(target, parent)
+        {
+            return;
+        }
+        actor.martin_tree.mov(target, parent).unwrap();
+        actor.evan_tree.mov(target, parent).unwrap();
+    }
+
+    fn apply(&mut self, pattern: Pattern) {
+        match pattern {
+            Pattern::Create { site, parent } => {
+                let peer = self.peer(site, 0);
+                let nodes = self.actors[peer].martin_tree.nodes().len();
+                let parent = match parent as usize % (nodes + 1) {
+                    0 => None,
+                    i => self.node(peer, i as u8 - 1),
+                };
+                let actor = &mut self.actors[peer];
+                actor.martin_tree.create(parent);
+                actor.evan_tree.create(parent);
+            }
+            Pattern::Swap { site, a, b } => {
+                let (p, q) = (self.peer(site, 0), self.peer(site, 1));
+                if let (Some(a), Some(b)) = (self.node(p, a), self.node(p, b)) {
+                    self.mov(p, a, b);
+                    self.mov(q, b, a);
+                }
+            }
+            Pattern::Rotate { site, a, b, c } => {
+                let p = self.peer(site, 0);
+                if let (Some(a), Some(b), Some(c)) =
+                    (self.node(p, a), self.node(p, b), self.node(p, c))
+                {
+                    self.mov(p, a, b);
+                    self.mov(self.peer(site, 1), b, c);
+                    self.mov(self.peer(site, 2), c, a);
+                }
+            }
+            Pattern::MoveAncestor { site, node, other } => {
+                let (p, q) = (self.peer(site, 0), self.peer(site, 1));
+                let (Some(node), Some(other)) = (self.node(p, node), self.node(p, other)) else {
+                    return;
+                };
+                let tree = &self.actors[p].martin_tree.algorithm;
+                let mut ancestor = node;
+                while let Some(parent) = tree.parent(ancestor).filter(|&p| p != ROOT_ID) {
+                    ancestor = parent;
+                }
+                if ancestor != node {
+                    self.mov(p, ancestor, other);
+                    self.mov(q, other, node);
+                }
+            }
+            Pattern::Sync { from, to } => {
+                let (from, to) = (self.peer(from, 0), self.peer(to, 0));
+                if from != to {
+                    let (a, b) = array_mut_ref!(&mut self.actors, [to, from]);
+                    a.martin_tree.merge(&b.martin_tree);
+                    a.evan_tree.merge(&b.evan_tree);
+                }
+            }
+            Pattern::SyncAll => self.sync_all(),
+        }
+    }
+
+    fn sync_all(&mut self) {
+        for i in 1..self.actors.len() {
+            let (a, b) = array_mut_ref!(&mut self.actors, [0, i]);
+            a.martin_tree.merge(&b.martin_tree);
+            a.evan_tree.merge(&b.evan_tree);
+        }
+        for i in 1..self.actors.len() {
+            let (a, b) = array_mut_ref!(&mut self.actors, [0, i]);
+            b.martin_tree.merge(&a.martin_tree);
+            b.evan_tree.merge(&a.evan_tree);
+        }
+    }
+
+    fn check(&mut self) {
+        self.sync_all();
+        for actor in &mut self.actors {
+            check_invariants(&mut actor.martin_tree).unwrap();
+            check_invariants(&mut actor.evan_tree).unwrap();
+        }
+        for actor in &self.actors[1..] {
+            let first = &self.actors[0];
+            assert_eq!(first.martin_tree.to_string(), actor.martin_tree.to_string());
+            assert_eq!(first.evan_tree.to_string(), actor.evan_tree.to_string());
+        }
+    }
+}
+
+/// Apply `actions` and check that every peer converges. If that fails, the
+/// actions are shrunk to a minimal failing sequence, which is printed as a
+/// `#[test]` that can be pasted into the tests below.
 pub fn fuzz_tree(site: usize, actions: &mut [Action]) {
-    let mut fuzzer = CRDTFuzzer::new(site);
+    run_or_shrink("fuzz_tree", site, actions, |actions, applied| {
+        let mut fuzzer = CRDTFuzzer::new(site);
+        for action in actions {
+            fuzzer.pre_process(action);
+            applied.push(*action);
+            fuzzer.apply(*action);
+        }
+        fuzzer.check_eq();
+    })
+}
+
+/// Apply `patterns` on `site` peers and check that every peer converges to
+/// a well formed tree. Failures are shrunk like in [`fuzz_tree`].
+pub fn fuzz_cycles(site: usize, patterns: &mut [Pattern]) {
+    run_or_shrink("fuzz_cycles", site, patterns, |patterns, applied| {
+        let mut fuzzer = CycleFuzzer::new(site);
+        for &pattern in patterns.iter() {
+            applied.push(pattern);
+            fuzzer.apply(pattern);
+        }
+        fuzzer.check();
+    })
+}
+
+thread_local! {
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f` without printing its panics. The panic hook is shared by every
+/// thread, so it is wrapped once to skip the threads in here instead of
+/// being swapped, which would silence the other tests running meanwhile.
+fn quietly<R>(f: impl FnOnce() -> R) -> R {
+    static WRAP_HOOK: Once = Once::new();
+    WRAP_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+    let quiet = QUIET.replace(true);
+    let ans = f();
+    QUIET.set(quiet);
+    ans
+}
+
+/// Run `run`, which records the inputs it applied. If it panics, shrink the
+/// applied inputs, print them as a test that calls `function` and panic
+/// again.
+fn run_or_shrink<T, F>(function: &str, site: usize, inputs: &mut [T], run: F)
+where
+    T: Copy + Debug,
+    F: Fn(&mut [T], &mut Vec<T>),
+{
+    let original = inputs.to_vec();
     let mut applied = Vec::new();
-    for action in actions {
-        fuzzer.pre_process(action);
-        applied.push(*action);
-        fuzzer.apply(*action);
+    // Shrinking panics over and over, so keep the panic hook quiet until it
+    // is done. This also stops the hook of `cargo fuzz` from aborting before
+    // the failure is shrunk.
+    let failure = quietly(|| {
+        let err = panic::catch_unwind(AssertUnwindSafe(|| run(inputs, &mut applied))).err()?;
+        let fails = |inputs: &[T]| {
+            let mut inputs = inputs.to_vec();
+            panic::catch_unwind(AssertUnwindSafe(|| run(&mut inputs, &mut Vec::new()))).is_err()
+        };
+        // `pre_process` runs again on the applied actions, which only
+        // usually maps them to themselves
+        let inputs = if fails(&applied) { applied } else { original };
+        Some((err, ddmin(inputs, fails)))
+    });
+    if let Some((err, minimal)) = failure {
+        let message = err
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| err.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        eprintln!("{} failed: {}", function, message);
+        eprintln!("{}", regression_test(function, site, &minimal));
+        panic::resume_unwind(err);
     }
-    // println!("{:?}", applied);
-    fuzzer.check_eq();
+}
+
+/// Delta debugging: find a subsequence of `items` that still `fails`, from
+/// which no single item can be removed.
+fn ddmin<T: Clone>(mut items: Vec<T>, mut fails: impl FnMut(&[T]) -> bool) -> Vec<T> {
+    let mut n = 2;
+    while items.len() >= 2 {
+        let chunk = items.len().div_ceil(n);
+        let chunks: Vec<_> = (0..items.len()).step_by(chunk).collect();
+        let subset = chunks
+            .iter()
+            .map(|&start| items[start..items.len().min(start + chunk)].to_vec())
+            .find(|subset| fails(subset));
+        if let Some(subset) = subset {
+            items = subset;
+            n = 2;
+            continue;
+        }
+        let complement = chunks
+            .iter()
+            .map(|&start| {
+                let mut rest = items[..start].to_vec();
+                rest.extend_from_slice(&items[items.len().min(start + chunk)..]);
+                rest
+            })
+            .find(|rest| fails(rest));
+        if let Some(complement) = complement {
+            items = complement;
+            n = (n - 1).max(2);
+            continue;
+        }
+        if n >= items.len() {
+            break;
+        }
+        n = (n * 2).min(items.len());
+    }
+    items
+}
+
+/// Format a test that calls `function` with `inputs`, the way rustfmt
+/// would. The inputs are enum variants, which print themselves as struct
+/// literals.
+fn regression_test<T: Debug>(function: &str, site: usize, inputs: &[T]) -> String {
+    let mut s = "#[test]\nfn regression() {\n".to_string();
+    // the tests below glob import the variants of `Action`
+    let name = std::any::type_name::<T>().rsplit("::").next().unwrap();
+    if name != "Action" {
+        s += &format!("    use {}::*;\n", name);
+    }
+    s += &format!("    {}(\n        {},\n        &mut [\n", function, site);
+    for input in inputs {
+        let literal = format!("{:?}", input);
+        let Some((name, fields)) = literal.strip_suffix(" }").and_then(|l| l.split_once(" { "))
+        else {
+            s += &format!("            {},\n", literal);
+            continue;
+        };
+        // rustfmt puts struct literals on one line up to 18 characters
+        if fields.len() <= 18 {
+            s += &format!("            {},\n", literal);
+        } else {
+            s += &format!("            {} {{\n", name);
+            for field in fields.split(", ") {
+                s += &format!("                {},\n", field);
+            }
+            s += "            },\n";
+        }
+    }
+    s += "        ],\n    )\n}\n";
+    s
 }
 
 #[cfg(test)]
@@ -249,4 +569,126 @@ mod test {
             ],
         )
     }
+
+    #[test]
+    fn shrink() {
+        let items: Vec<u32> = (0..20).collect();
+        let minimal = ddmin(items, |items| items.contains(&3) && items.contains(&11));
+        assert_eq!(minimal, vec![3, 11]);
+    }
+
+    #[test]
+    fn print_regression_test() {
+        let actions = [
+            Create {
+                site: 51,
+                parent: 0,
+            },
+            Create { site: 4, parent: 0 },
+            Sync,
+            Move {
+                site: 5,
+                target: 6,
+                parent: 7,
+            },
+        ];
+        let expected = r#"#[test]
+fn regression() {
+    fuzz_tree(
+        5,
+        &mut [
+            Create {
+                site: 51,
+                parent: 0,
+            },
+            Create { site: 4, parent: 0 },
+            Sync,
+            Move {
+                site: 5,
+                target: 6,
+                parent: 7,
+            },
+        ],
+    )
+}
+"#;
+        assert_eq!(regression_test("fuzz_tree", 5, &actions), expected);
+    }
+
+    #[test]
+    fn cycles() {
+        use Pattern::*;
+        fuzz_cycles(
+            3,
+            &mut [
+                Create {
+                    site: 40,
+                    parent: 115,
+                },
+                Create {
+                    site: 153,
+                    parent: 142,
+                },
+                SyncAll,
+                Swap {
+                    site: 99,
+                    a: 63,
+                    b: 106,
+                },
+                Create {
+                    site: 32,
+                    parent: 13,
+                },
+                SyncAll,
+                Create {
+                    site: 119,
+                    parent: 84,
+                },
+                Rotate {
+                    site: 230,
+                    a: 32,
+                    b: 15,
+                    c: 194,
+                },
+            ],
+        )
+    }
+
+    #[test]
+    fn random_cycles() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let mut patterns: Vec<Pattern> = (0..40)
+                .map(|_| match rng.gen_range(0..8) {
+                    0 | 1 => Pattern::Create {
+                        site: rng.gen(),
+                        parent: rng.gen(),
+                    },
+                    2 => Pattern::Swap {
+                        site: rng.gen(),
+                        a: rng.gen(),
+                        b: rng.gen(),
+                    },
+                    3 => Pattern::Rotate {
+                        site: rng.gen(),
+                        a: rng.gen(),
+                        b: rng.gen(),
+                        c: rng.gen(),
+                    },
+                    4 => Pattern::MoveAncestor {
+                        site: rng.gen(),
+                        node: rng.gen(),
+                        other: rng.gen(),
+                    },
+                    5 | 6 => Pattern::Sync {
+                        from: rng.gen(),
+                        to: rng.gen(),
+                    },
+                    _ => Pattern::SyncAll,
+                })
+                .collect();
+            fuzz_cycles(3, &mut patterns);
+        }
+    }
 }
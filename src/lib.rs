@@ -1,48 +1,68 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    hash::Hash,
+    sync::Arc,
+};
 
-use fxhash::FxHashMap;
+use clock::Clock;
+use encoding::{DecodeError, Reader};
+use fxhash::{FxHashMap, FxHashSet};
+use rand::Rng;
+pub mod analysis;
+pub mod ancestor;
+mod arena;
+pub mod clock;
+pub mod encoding;
 pub mod evan;
 #[cfg(feature = "fuzz")]
 pub mod fuzz;
 pub mod martin;
+pub mod model;
+#[cfg(feature = "p2pdb")]
+pub mod p2pdb;
+pub mod scenario;
+pub mod sim;
 
 pub const ROOT_ID: NodeID = NodeID {
-    lamport: u32::MAX,
+    lamport: u64::MAX,
     peer: u64::MAX,
 };
 
+/// The largest lamport an op can have. Imports reject ops beyond it, and the
+/// lamport counter of a replica only follows other peers up to half of it, so
+/// no peer can run another one out of op IDs.
+pub const MAX_LAMPORT: u64 = u64::MAX >> 1;
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub struct TreeNode {
-    id: NodeID,
-    children: Vec<TreeNode>,
+pub struct TreeNode<K = NodeID> {
+    id: K,
+    children: Vec<TreeNode<K>>,
 }
 
-impl TreeNode {
-    pub fn from_state(state: &FxHashMap<NodeID, Option<NodeID>>) -> TreeNode {
-        let root_id = state
-            .iter()
-            .find_map(|(id, parent)| if parent.is_none() { Some(*id) } else { None })
-            .expect("No root node found");
+impl<K: NodeKey> TreeNode<K> {
+    pub(crate) fn new(id: K, children: Vec<TreeNode<K>>) -> Self {
+        TreeNode { id, children }
+    }
 
-        TreeNode::build_tree(root_id, state)
+    pub fn from_state(state: &FxHashMap<K, Option<K>>) -> TreeNode<K> {
+        let mut children: FxHashMap<K, Vec<K>> = FxHashMap::default();
+        for (&id, &parent) in state {
+            if let Some(parent) = parent {
+                children.entry(parent).or_default().push(id);
+            }
+        }
+        TreeNode::build_tree(K::root(), &children)
     }
 
-    fn build_tree(node_id: NodeID, state: &FxHashMap<NodeID, Option<NodeID>>) -> TreeNode {
-        let mut children = state
-            .iter()
-            .filter_map(|(id, parent)| {
-                if Some(node_id) == *parent {
-                    Some(TreeNode::build_tree(*id, state))
-                } else {
-                    None
-                }
-            })
+    fn build_tree(node_id: K, children: &FxHashMap<K, Vec<K>>) -> TreeNode<K> {
+        let mut nodes = children
+            .get(&node_id)
+            .into_iter()
+            .flatten()
+            .map(|&id| TreeNode::build_tree(id, children))
             .collect::<Vec<_>>();
-        children.sort();
-        TreeNode {
-            id: node_id,
-            children,
-        }
+        nodes.sort();
+        TreeNode::new(node_id, nodes)
     }
 }
 
@@ -55,7 +75,7 @@ impl Display for NodeID {
     }
 }
 
-impl TreeNode {
+impl<K: NodeKey> TreeNode<K> {
     fn to_string(&self, prefix: String, last: bool) -> String {
         let connector = if last { "└── " } else { "├── " };
         let mut s = format!("{}{}{}\n", prefix, connector, self.id);
@@ -74,15 +94,15 @@ impl TreeNode {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ID {
-    pub lamport: u32,
+    pub lamport: u64,
     pub peer: u64,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct NodeID {
-    pub lamport: u32,
+    pub lamport: u64,
     pub peer: u64,
 }
 
@@ -95,112 +115,462 @@ impl From<ID> for NodeID {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum TreeOp {
+/// The type used to identify nodes. The default is [`NodeID`], taken from the
+/// op that created the node, but applications can use their own stable IDs
+/// instead, such as UUIDs stored in a `u128`.
+pub trait NodeKey: Copy + Eq + Ord + Hash + Debug + Display + 'static {
+    /// The key of the root node every tree starts with.
+    fn root() -> Self;
+    /// The key of a node created by the op `id` without an explicit key.
+    /// Only needed to read logs encoded before node keys were stored.
+    fn from_op_id(_id: ID) -> Option<Self> {
+        None
+    }
+    /// The key of the node created by [`MovableTree::create_with_key`] for
+    /// the application key `key`. It must depend on nothing but `key` so that
+    /// every peer derives the same node, and must never be [`NodeKey::root`].
+    fn from_user_key(key: &[u8]) -> Self;
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+impl NodeKey for NodeID {
+    fn root() -> Self {
+        ROOT_ID
+    }
+
+    fn from_op_id(id: ID) -> Option<Self> {
+        Some(id.into())
+    }
+
+    fn from_user_key(key: &[u8]) -> Self {
+        // No peer can use the root's peer ID, so these never clash with the
+        // IDs of nodes created by `MovableTree::create`.
+        NodeID {
+            lamport: stable_hash(key, 0).min(u64::MAX - 1),
+            peer: ROOT_ID.peer,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encoding::write_varint(buf, self.lamport);
+        encoding::write_varint(buf, self.peer);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(NodeID {
+            lamport: reader.read_varint()?,
+            peer: reader.read_varint()?,
+        })
+    }
+}
+
+impl NodeKey for u128 {
+    fn root() -> Self {
+        u128::MAX
+    }
+
+    fn from_user_key(key: &[u8]) -> Self {
+        let hash = (stable_hash(key, 0) as u128) << 64 | stable_hash(key, 1) as u128;
+        hash.min(u128::MAX - 1)
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let bytes = reader.read_array()?;
+        Ok(u128::from_le_bytes(bytes))
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std and fx hashers gives the same result
+/// on every platform and compiler version.
+fn stable_hash(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf29ce484222325 ^ seed;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeOp<K = NodeID> {
     Create {
-        parent: NodeID,
+        target: K,
+        parent: K,
     },
     Move {
-        target: NodeID,
-        parent: NodeID,
+        target: K,
+        parent: K,
         counter: u32,
     },
+    /// Delete the edge from `target` to `parent`, as undo does in the JS
+    /// reference. Only Evan's algorithm has edges; Kleppmann's ignores it.
+    RemoveEdge {
+        target: K,
+        parent: K,
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Op {
+impl<K: Copy> TreeOp<K> {
+    /// The node created or moved by the op.
+    pub fn target(&self) -> K {
+        match *self {
+            TreeOp::Create { target, .. }
+            | TreeOp::Move { target, .. }
+            | TreeOp::RemoveEdge { target, .. } => target,
+        }
+    }
+
+    pub fn parent(&self) -> K {
+        match *self {
+            TreeOp::Create { parent, .. }
+            | TreeOp::Move { parent, .. }
+            | TreeOp::RemoveEdge { parent, .. } => parent,
+        }
+    }
+}
+
+/// Opaque application data attached to an op, such as who made an edit and
+/// why. It travels with the op through merges and the op logs but never takes
+/// part in conflict resolution.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpMeta {
+    pub author: Option<String>,
+    /// Wall-clock time in milliseconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+    pub message: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Op<K = NodeID> {
     id: ID,
-    op: TreeOp,
+    op: TreeOp<K>,
+    meta: Option<Arc<OpMeta>>,
+}
+
+impl<K: NodeKey> Op<K> {
+    pub fn id(&self) -> ID {
+        self.id
+    }
+
+    pub fn op(&self) -> TreeOp<K> {
+        self.op
+    }
+
+    pub fn meta(&self) -> Option<&OpMeta> {
+        self.meta.as_deref()
+    }
+
+    /// Unlike `==`, which only compares IDs, also compares what the ops do.
+    pub fn same_content(&self, other: &Op<K>) -> bool {
+        self.id == other.id && self.op == other.op && self.meta == other.meta
+    }
 }
 
-impl PartialEq for Op {
+impl<K> PartialEq for Op<K> {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
 }
 
-impl Eq for Op {}
+impl<K> Eq for Op<K> {}
 
-impl Ord for Op {
+impl<K> Ord for Op<K> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.id.cmp(&other.id)
     }
 }
 
-impl PartialOrd for Op {
+impl<K> PartialOrd for Op<K> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.id.cmp(&other.id))
+        Some(self.cmp(other))
+    }
+}
+
+/// What happened to concurrent edits during a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeReport<K = NodeID> {
+    /// Local moves that were in effect before the merge but lost to a
+    /// concurrent move of the same node.
+    pub overridden: Vec<OverriddenMove<K>>,
+    /// Moves that became no-ops because they would have created a cycle.
+    /// Only reported by Kleppmann's algorithm.
+    pub cycle_skipped: Vec<ID>,
+    /// Nodes reattached to break a cycle, with the parent they ended up
+    /// under. Only reported by Evan's algorithm.
+    pub rescued: Vec<(K, K)>,
+    /// Peers whose incoming ops conflict with ops already known under the
+    /// same ID, which means two clients are using the same peer ID. Their
+    /// ops are not merged. Ops of other peers are, even if they touch nodes
+    /// created by the skipped ops, which then don't exist in Kleppmann's
+    /// algorithm and get a parent edge in Evan's.
+    pub collisions: Vec<PeerCollision>,
+    /// Quarantined peers whose new ops were rejected. See
+    /// [`MovableTree::quarantine_peer`].
+    pub quarantined: Vec<u64>,
+}
+
+impl<K> Default for MergeReport<K> {
+    fn default() -> Self {
+        MergeReport {
+            overridden: Vec::new(),
+            cycle_skipped: Vec::new(),
+            rescued: Vec::new(),
+            collisions: Vec::new(),
+            quarantined: Vec::new(),
+        }
     }
 }
 
-pub trait MovableTreeAlgorithm {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCollision {
+    pub peer: u64,
+    /// The ID shared by two different ops.
+    pub id: ID,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverriddenMove<K = NodeID> {
+    pub id: ID,
+    pub target: K,
+    /// The parent the local move put the node under.
+    pub parent: K,
+    /// The parent the node has after the merge.
+    pub current_parent: Option<K>,
+}
+
+/// A create or move op that targeted a node, as listed by
+/// [`MovableTree::history`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MoveRecord<K = NodeID> {
+    pub id: ID,
+    pub parent: K,
+    pub create: bool,
+    /// Whether the op still has an effect on the tree. See
+    /// [`MovableTreeAlgorithm::is_effective`].
+    pub effective: bool,
+    pub meta: Option<Arc<OpMeta>>,
+}
+
+/// What decides the current parent of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentCause<K = NodeID> {
+    /// The last create or move op that took effect on the node, in
+    /// Kleppmann's algorithm.
+    Op(ID),
+    /// The edge the node hangs from in Evan's algorithm. `rescued` is true
+    /// if it is not the node's most recent edge, because that one would have
+    /// created a cycle.
+    Edge {
+        parent: K,
+        counter: evan::EdgeCounter,
+        rescued: bool,
+    },
+}
+
+pub trait MovableTreeAlgorithm<K: NodeKey = NodeID> {
     fn new() -> Self;
     // return ops for evan's algorithm
-    fn apply(&mut self, op: Op, local: bool) -> Vec<Op>;
-    fn merge(&mut self, ops: Vec<Op>);
-    fn nodes(&self) -> Vec<NodeID>;
-    fn parent(&self, node: NodeID) -> Option<NodeID>;
-    fn get_root(&self) -> TreeNode;
-    fn is_ancestor_of(&self, maybe_ancestor: NodeID, mut node_id: NodeID) -> bool {
-        if maybe_ancestor == node_id {
-            return true;
-        }
+    fn apply(&mut self, op: Op<K>, local: bool) -> Vec<Op<K>>;
+    fn merge(&mut self, ops: Vec<Op<K>>) -> MergeReport<K>;
+    fn nodes(&self) -> Vec<K>;
+    /// Whether `node` is one of [`MovableTreeAlgorithm::nodes`], without
+    /// listing them all.
+    fn contains(&self, node: K) -> bool {
+        self.nodes().contains(&node)
+    }
+    fn parent(&self, node: K) -> Option<K>;
+    fn get_root(&self) -> TreeNode<K>;
+    /// Whether `op` still has an effect: for Kleppmann's algorithm, the move
+    /// was not skipped as a cycle; for Evan's, its edge was not overwritten.
+    fn is_effective(&self, op: &Op<K>) -> bool;
+    fn explain_parent(&self, node: K) -> Option<ParentCause<K>>;
+    fn is_ancestor_of(&self, maybe_ancestor: K, node_id: K) -> bool {
+        walk_is_ancestor_of(self, maybe_ancestor, node_id)
+    }
+    /// The same check, for implementations with an index that has to be
+    /// updated by queries. Used before local moves.
+    fn is_ancestor_of_mut(&mut self, maybe_ancestor: K, node_id: K) -> bool {
+        self.is_ancestor_of(maybe_ancestor, node_id)
+    }
+}
 
-        loop {
-            let parent = self.parent(node_id);
-            match parent {
-                Some(parent_id) if parent_id == maybe_ancestor => return true,
-                Some(parent_id) if parent_id == node_id => panic!("loop detected"),
-                Some(parent_id) => {
-                    node_id = parent_id;
-                }
-                None => return false,
+/// The default ancestor check, walking parent pointers one at a time.
+pub fn walk_is_ancestor_of<K: NodeKey, T: MovableTreeAlgorithm<K> + ?Sized>(
+    tree: &T,
+    maybe_ancestor: K,
+    mut node_id: K,
+) -> bool {
+    if maybe_ancestor == node_id {
+        return true;
+    }
+
+    loop {
+        let parent = tree.parent(node_id);
+        match parent {
+            Some(parent_id) if parent_id == maybe_ancestor => return true,
+            Some(parent_id) if parent_id == node_id => panic!("loop detected"),
+            Some(parent_id) => {
+                node_id = parent_id;
             }
+            None => return false,
         }
     }
 }
 
-pub struct MovableTree<T> {
+/// The number of low bits of a lamport that count ops within the same tick of
+/// a hybrid logical clock, see [`MovableTree::with_clock`].
+pub const LOGICAL_BITS: u32 = 16;
+
+pub struct MovableTree<T, K = NodeID> {
     pub algorithm: T,
     peer: u64,
-    ops: FxHashMap<u64, Vec<Op>>,
-    next_lamport: u32,
+    ops: FxHashMap<u64, Vec<Op<K>>>,
+    next_lamport: u64,
+    clock: Option<Box<dyn Clock>>,
+    quarantined: FxHashSet<u64>,
 }
 
-impl<T: MovableTreeAlgorithm> MovableTree<T> {
+impl<K: NodeKey, T: MovableTreeAlgorithm<K>> MovableTree<T, K> {
     pub fn new(peer: u64) -> Self {
+        assert_ne!(peer, ROOT_ID.peer, "peer ID {} is reserved", peer);
         MovableTree {
             algorithm: T::new(),
             ops: FxHashMap::default(),
             peer,
             next_lamport: 0,
+            clock: None,
+            quarantined: FxHashSet::default(),
         }
     }
 
+    /// Create a tree with a peer ID drawn from `rng`.
+    pub fn with_random_peer<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        loop {
+            let peer = rng.gen();
+            if peer != ROOT_ID.peer {
+                return Self::new(peer);
+            }
+        }
+    }
+
+    /// Create a tree with a random peer ID.
+    pub fn new_random_peer() -> Self {
+        Self::with_random_peer(&mut rand::thread_rng())
+    }
+
+    pub fn peer(&self) -> u64 {
+        self.peer
+    }
+
+    /// Generate op IDs from a hybrid logical clock instead of a plain lamport
+    /// counter. The lamport of a new op holds the physical time from `clock`
+    /// in its high bits and a logical counter in the low [`LOGICAL_BITS`]
+    /// ones. The logical counter orders ops within the same tick, or after
+    /// ops of a peer whose clock is ahead, and never spills into the physical
+    /// time unless a single tick has more ops than it can count. IDs still
+    /// obey the lamport rules and are ordered the same way, but an op made
+    /// later in real time now generally beats an older one even if its peer
+    /// was offline for a while.
+    pub fn with_clock(peer: u64, clock: impl Clock + 'static) -> Self {
+        let mut tree = Self::new(peer);
+        tree.clock = Some(Box::new(clock));
+        tree
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the lamport counter passes [`MAX_LAMPORT`], which takes
+    /// about `2^62` local ops, as other peers and the clock can only move it
+    /// up to half of that.
     pub fn new_id(&mut self) -> ID {
+        if let Some(clock) = &mut self.clock {
+            let physical = clock.now().saturating_mul(1 << LOGICAL_BITS);
+            self.advance_to(physical);
+        }
         let id = ID {
             lamport: self.next_lamport,
             peer: self.peer,
         };
+        assert!(
+            id.lamport <= MAX_LAMPORT,
+            "lamport counter overflow: no more op IDs can be generated"
+        );
         self.next_lamport += 1;
         id
     }
 
-    pub fn create(&mut self, parent: Option<NodeID>) -> NodeID {
-        let parent = parent.unwrap_or(ROOT_ID);
+    /// Make new op IDs start at `next` or later. Lamports from other peers or
+    /// the clock are only followed up to half of [`MAX_LAMPORT`], so that the
+    /// other half is always left for local ops.
+    fn advance_to(&mut self, next: u64) {
+        self.next_lamport = self.next_lamport.max(next.min(MAX_LAMPORT / 2));
+    }
+
+    /// Create a node with an application-supplied key. Fails if a node with
+    /// this key already exists.
+    #[allow(clippy::result_unit_err)]
+    pub fn create_with_id(&mut self, parent: Option<K>, id: K) -> Result<(), ()> {
+        if id == K::root() || self.algorithm.contains(id) {
+            return Err(());
+        }
+        self.create_op(parent, |_| id, None);
+        Ok(())
+    }
+
+    /// Create the node identified by the application key `key`, or move it
+    /// under `parent` if it already exists. The node ID is derived from `key`
+    /// alone, so peers that concurrently create the same key end up with a
+    /// single node. Fails if moving the existing node would create a cycle.
+    #[allow(clippy::result_unit_err)]
+    pub fn create_with_key(&mut self, parent: Option<K>, key: &[u8]) -> Result<K, ()> {
+        let id = K::from_user_key(key);
+        let parent = parent.unwrap_or(K::root());
+        match self.algorithm.parent(id) {
+            None => {
+                self.create_op(Some(parent), |_| id, None);
+            }
+            Some(old_parent) if old_parent != parent => self.mov(id, parent)?,
+            Some(_) => {}
+        }
+        Ok(id)
+    }
+
+    fn create_op(
+        &mut self,
+        parent: Option<K>,
+        target: impl FnOnce(ID) -> K,
+        meta: Option<Arc<OpMeta>>,
+    ) -> K {
+        let parent = parent.unwrap_or(K::root());
         let id = self.new_id();
+        let target = target(id);
         let op = Op {
             id,
-            op: TreeOp::Create { parent },
+            op: TreeOp::Create { target, parent },
+            meta,
         };
-        self.ops.entry(self.peer).or_default().push(op);
+        self.ops.entry(self.peer).or_default().push(op.clone());
         self.algorithm.apply(op, true);
-        id.into()
+        target
     }
 
     #[allow(clippy::result_unit_err)]
-    pub fn mov(&mut self, target: NodeID, parent: NodeID) -> Result<(), ()> {
-        if self.algorithm.is_ancestor_of(target, parent) {
+    pub fn mov(&mut self, target: K, parent: K) -> Result<(), ()> {
+        self.mov_op(target, parent, None)
+    }
+
+    #[allow(clippy::result_unit_err)]
+    pub fn mov_with_meta(&mut self, target: K, parent: K, meta: OpMeta) -> Result<(), ()> {
+        self.mov_op(target, parent, Some(Arc::new(meta)))
+    }
+
+    fn mov_op(&mut self, target: K, parent: K, meta: Option<Arc<OpMeta>>) -> Result<(), ()> {
+        if self.algorithm.is_ancestor_of_mut(target, parent) {
             return Err(());
         }
         let op = Op {
@@ -210,47 +580,392 @@ impl<T: MovableTreeAlgorithm> MovableTree<T> {
                 parent,
                 counter: 0,
             },
+            meta,
         };
         let ops = self.algorithm.apply(op, true);
         self.ops.entry(self.peer).or_default().extend(ops);
         Ok(())
     }
 
-    pub fn merge(&mut self, other: &Self) {
+    /// Merge `other`. The report has everything but
+    /// [`MergeReport::overridden`], which takes a scan of the local ops and is
+    /// only filled in by [`MovableTree::merge_with_report`]. Check its
+    /// [`MergeReport::collisions`]: ops of peers that collided are not
+    /// merged.
+    pub fn merge(&mut self, other: &Self) -> MergeReport<K> {
+        self.merge_ops(&other.ops)
+    }
+
+    /// Merge `other` and report which edits were overridden or changed by
+    /// conflict resolution.
+    pub fn merge_with_report(&mut self, other: &Self) -> MergeReport<K> {
+        self.merge_ops_with_report(&other.ops)
+    }
+
+    /// Encode the op logs of every peer, see [`encoding`] for the format.
+    pub fn export(&self) -> Vec<u8> {
+        encoding::encode_ops(&self.ops)
+    }
+
+    /// Merge op logs encoded by [`MovableTree::export`], with the same
+    /// report as [`MovableTree::merge`].
+    pub fn import(&mut self, bytes: &[u8]) -> Result<MergeReport<K>, DecodeError> {
+        let ops = encoding::decode_ops(bytes)?;
+        Ok(self.merge_ops(&ops))
+    }
+
+    fn merge_ops_with_report(&mut self, ops: &FxHashMap<u64, Vec<Op<K>>>) -> MergeReport<K> {
+        // the latest local move of every node, if it is still in effect
+        let mut local_moves: FxHashMap<K, (ID, K)> = FxHashMap::default();
+        for op in self.ops.get(&self.peer).into_iter().flatten() {
+            if let TreeOp::Move { target, parent, .. } = op.op {
+                local_moves.insert(target, (op.id, parent));
+            }
+        }
+        local_moves
+            .retain(|&target, &mut (_, parent)| self.algorithm.parent(target) == Some(parent));
+
+        let mut report = self.merge_ops(ops);
+        for (target, (id, parent)) in local_moves {
+            let current_parent = self.algorithm.parent(target);
+            if current_parent != Some(parent)
+                && !report.cycle_skipped.contains(&id)
+                && !report.rescued.iter().any(|(node, _)| *node == target)
+            {
+                report.overridden.push(OverriddenMove {
+                    id,
+                    target,
+                    parent,
+                    current_parent,
+                });
+            }
+        }
+        report.overridden.sort_by_key(|m| m.id);
+        report
+    }
+
+    fn merge_ops(&mut self, ops: &FxHashMap<u64, Vec<Op<K>>>) -> MergeReport<K> {
         let mut ans = Vec::new();
-        for (peer, ops) in other.ops.iter() {
+        let mut collisions = Vec::new();
+        let mut quarantined = Vec::new();
+        for (peer, ops) in ops.iter() {
             let self_start = self.ops.get(peer).map(|v| v.len()).unwrap_or(0);
+            if self.quarantined.contains(peer) {
+                if ops.len() > self_start {
+                    quarantined.push(*peer);
+                }
+                continue;
+            }
+            if let Some(id) = self.find_collision(*peer, ops) {
+                collisions.push(PeerCollision { peer: *peer, id });
+                continue;
+            }
             if ops.len() > self_start {
-                let entry = self.ops.entry(*peer).or_default();
-                for &op in &ops[self_start..] {
-                    entry.push(op);
-                    ans.push(op);
-                    if op.id.lamport >= self.next_lamport {
-                        self.next_lamport = op.id.lamport + 1;
-                    }
+                let new = &ops[self_start..];
+                if let Some(max) = new.iter().map(|op| op.id.lamport).max() {
+                    self.advance_to(max.saturating_add(1));
                 }
+                self.ops.entry(*peer).or_default().extend_from_slice(new);
+                ans.extend_from_slice(new);
             }
         }
-        self.algorithm.merge(ans);
+        let mut report = self.algorithm.merge(ans);
+        collisions.sort_by_key(|c| c.peer);
+        report.collisions = collisions;
+        quarantined.sort();
+        report.quarantined = quarantined;
+        report
     }
 
-    pub fn nodes(&self) -> Vec<NodeID> {
+    /// Logs of the same peer must agree on their common prefix, and nobody
+    /// else can have ops of this peer that it does not know about. Only the
+    /// last op of the common prefix is compared to keep merges cheap, which
+    /// catches two clients sharing a peer ID as soon as both of them have
+    /// made an edit.
+    fn find_collision(&self, peer: u64, ops: &[Op<K>]) -> Option<ID> {
+        let known = self.ops.get(&peer).map(|v| v.as_slice()).unwrap_or(&[]);
+        if peer == self.peer && ops.len() > known.len() {
+            return Some(ops[known.len()].id);
+        }
+        let common = known.len().min(ops.len());
+        if common > 0 && !known[common - 1].same_content(&ops[common - 1]) {
+            return Some(ops[common - 1].id);
+        }
+        None
+    }
+
+    /// Every create or move op that targeted `node`, in op order.
+    pub fn history(&self, node: K) -> Vec<MoveRecord<K>> {
+        let mut records: Vec<MoveRecord<K>> = self
+            .ops
+            .values()
+            .flatten()
+            .filter_map(|op| {
+                let (parent, create) = match op.op {
+                    TreeOp::Create { target, parent } if target == node => (parent, true),
+                    TreeOp::Move { target, parent, .. } if target == node => (parent, false),
+                    _ => return None,
+                };
+                Some(MoveRecord {
+                    id: op.id,
+                    parent,
+                    create,
+                    effective: self.algorithm.is_effective(op),
+                    meta: op.meta.clone(),
+                })
+            })
+            .collect();
+        records.sort_by_key(|r| (r.id, r.parent));
+        records.dedup_by_key(|r| (r.id, r.parent));
+        records
+    }
+
+    /// The op or edge that decides why `node` is under its current parent.
+    pub fn explain_parent(&self, node: K) -> Option<ParentCause<K>> {
+        self.algorithm.explain_parent(node)
+    }
+
+    pub fn nodes(&self) -> Vec<K> {
         self.algorithm
             .nodes()
             .into_iter()
-            .filter(|n| *n != ROOT_ID)
+            .filter(|n| *n != K::root())
             .collect()
     }
 
-    pub fn is_ancestor_of(&self, target: NodeID, parent: NodeID) -> bool {
-        self.algorithm.is_ancestor_of(target, parent)
+    /// Takes `&mut self` so that an ancestor index, such as the one of
+    /// [`martin::MartinTree::enable_ancestor_index`], can answer it.
+    pub fn is_ancestor_of(&mut self, target: K, parent: K) -> bool {
+        self.algorithm.is_ancestor_of_mut(target, parent)
+    }
+
+    /// Reject the ops of `peer` that are not known yet in every later merge,
+    /// including the ones relayed by other peers.
+    pub fn quarantine_peer(&mut self, peer: u64) {
+        assert_ne!(peer, self.peer, "can't quarantine the local peer");
+        self.quarantined.insert(peer);
+    }
+
+    pub fn is_quarantined(&self, peer: u64) -> bool {
+        self.quarantined.contains(&peer)
+    }
+
+    /// Replay the op logs without the ops of `peer` from lamport `since` on.
+    fn replay_without(&self, peer: u64, since: u64) -> T {
+        let mut shadow = T::new();
+        let ops = self
+            .ops
+            .iter()
+            .flat_map(|(&p, ops)| {
+                ops.iter()
+                    .filter(move |op| p != peer || op.id.lamport < since)
+            })
+            .cloned()
+            .collect();
+        shadow.merge(ops);
+        shadow
+    }
+
+    /// Move every node under the root of `shadow` to its parent there.
+    /// Parents are moved before their children, so a node's new parent is
+    /// already where it belongs and none of the moves can create a cycle.
+    /// Returns the IDs of the moves.
+    fn move_like(&mut self, shadow: &T) -> Vec<ID> {
+        let mut children: FxHashMap<K, Vec<K>> = FxHashMap::default();
+        for node in shadow.nodes() {
+            if let Some(parent) = shadow.parent(node) {
+                children.entry(parent).or_default().push(node);
+            }
+        }
+        let mut ans = Vec::new();
+        let mut queue = vec![K::root()];
+        while let Some(parent) = queue.pop() {
+            let Some(mut nodes) = children.remove(&parent) else {
+                continue;
+            };
+            nodes.sort();
+            for &node in &nodes {
+                if self.algorithm.parent(node) != Some(parent) {
+                    self.mov(node, parent)
+                        .expect("the parent is not under the node in the shadow tree");
+                    ans.push(self.ops[&self.peer].last().unwrap().id);
+                }
+            }
+            queue.extend(nodes);
+        }
+        ans
+    }
+}
+
+impl<K: NodeKey + From<ID>, T: MovableTreeAlgorithm<K>> MovableTree<T, K> {
+    pub fn create(&mut self, parent: Option<K>) -> K {
+        self.create_op(parent, K::from, None)
+    }
+
+    pub fn create_with_meta(&mut self, parent: Option<K>, meta: OpMeta) -> K {
+        self.create_op(parent, K::from, Some(Arc::new(meta)))
+    }
+
+    /// Duplicate the subtree rooted at `source` under `new_parent`.
+    ///
+    /// The shape of the subtree is read before any op is emitted, so the copy
+    /// is always a complete snapshot even if the source is being moved around
+    /// concurrently. Every copied node gets a fresh ID. Returns the ID of the
+    /// copy of `source` and the mapping from original to copied IDs.
+    #[allow(clippy::result_unit_err, clippy::type_complexity)]
+    pub fn copy_subtree(&mut self, source: K, new_parent: K) -> Result<(K, FxHashMap<K, K>), ()> {
+        let all_nodes = self.algorithm.nodes();
+        if source == K::root() || !all_nodes.contains(&source) || !all_nodes.contains(&new_parent) {
+            return Err(());
+        }
+
+        let mut children: FxHashMap<K, Vec<K>> = FxHashMap::default();
+        for node in all_nodes {
+            if let Some(parent) = self.algorithm.parent(node) {
+                children.entry(parent).or_default().push(node);
+            }
+        }
+        // Parents are always visited before their children, and siblings in
+        // ID order, so the copy is laid out deterministically.
+        let mut order = vec![source];
+        let mut i = 0;
+        while i < order.len() {
+            if let Some(c) = children.get_mut(&order[i]) {
+                c.sort();
+                order.extend_from_slice(c);
+            }
+            i += 1;
+        }
+
+        let mut mapping = FxHashMap::default();
+        for node in order {
+            let parent = if node == source {
+                new_parent
+            } else {
+                mapping[&self.algorithm.parent(node).unwrap()]
+            };
+            let copy = self.create(Some(parent));
+            mapping.insert(node, copy);
+        }
+        Ok((mapping[&source], mapping))
+    }
+}
+
+impl<K: NodeKey> MovableTree<martin::MartinTree<K>, K> {
+    /// Undo the create or move op `id`, even if later ops have moved the node
+    /// since, by moving the node back to the parent it had right before the
+    /// op. The undo is a new move op that syncs like any other, so undoing it
+    /// in turn redoes `id`. Returns the ID of the new op.
+    ///
+    /// Fails if the op is unknown or was skipped, if it created the node, or
+    /// if the old parent is no longer a valid parent because it now lives in
+    /// the subtree of the node.
+    #[allow(clippy::result_unit_err)]
+    pub fn undo(&mut self, id: ID) -> Result<ID, ()> {
+        let (target, parent) = self.algorithm.parent_before(id).ok_or(())?;
+        self.mov(target, parent.ok_or(())?)?;
+        Ok(self.ops[&self.peer].last().unwrap().id)
+    }
+
+    /// Undo every op of `peer` with a lamport of at least `since` by moving
+    /// nodes back to where they would be without those ops. The moves are
+    /// new ops that sync like any other; the reverted ops stay in the log.
+    /// Nodes the peer created stay in the tree, as this algorithm can't
+    /// delete nodes, but nothing else is left under them. Returns the IDs of
+    /// the new ops.
+    ///
+    /// Only meaningful if the op logs hold every op, so call
+    /// [`MovableTree::quarantine_peer`] first to keep the peer from adding
+    /// more ops in later merges.
+    pub fn revert_peer(&mut self, peer: u64, since: u64) -> Vec<ID> {
+        let shadow = self.replay_without(peer, since);
+        self.move_like(&shadow)
+    }
+}
+
+/// Sync by exchanging edge state instead of op logs. Replicas that sync this
+/// way never receive each other's ops, so their op logs only hold local ops
+/// and must not be merged with [`MovableTree::merge`] afterwards.
+impl<K: NodeKey> MovableTree<evan::EvanTree<K>, K> {
+    pub fn merge_state(&mut self, other: &Self) -> MergeReport<K> {
+        let report = self.algorithm.merge_state(&other.algorithm);
+        self.observe_version();
+        report
+    }
+
+    pub fn export_state(&self) -> Vec<u8> {
+        self.algorithm.export_state()
+    }
+
+    /// Remove the edge from `target` to `parent`. The node falls back to its
+    /// next most recent edge, or is detached from the tree if it has none
+    /// left. Fails if there is no such edge.
+    #[allow(clippy::result_unit_err)]
+    pub fn remove_edge(&mut self, target: K, parent: K) -> Result<(), ()> {
+        if !self
+            .algorithm
+            .edges(target)
+            .iter()
+            .any(|&(p, _)| p == parent)
+        {
+            return Err(());
+        }
+        let op = Op {
+            id: self.new_id(),
+            op: TreeOp::RemoveEdge { target, parent },
+            meta: None,
+        };
+        self.ops.entry(self.peer).or_default().push(op.clone());
+        self.algorithm.apply(op, true);
+        Ok(())
+    }
+
+    /// Undo every op of `peer` with a lamport of at least `since`, like
+    /// [`MovableTree::revert_peer`] does for Kleppmann's algorithm. Nodes are
+    /// moved back to where they would be without those ops, and nodes that
+    /// would not be in the tree at all, such as the ones the peer created,
+    /// are detached by removing their edges.
+    ///
+    /// This replays the op logs, so it only works for replicas that sync by
+    /// merging ops rather than edge state.
+    pub fn revert_peer(&mut self, peer: u64, since: u64) -> Vec<ID> {
+        let shadow = self.replay_without(peer, since);
+        let mut ans = self.move_like(&shadow);
+        for node in self.nodes() {
+            if self.algorithm.parent(node).is_some() && shadow.parent(node).is_none() {
+                for (parent, _) in self.algorithm.edges(node) {
+                    self.remove_edge(node, parent).unwrap();
+                    ans.push(self.ops[&self.peer].last().unwrap().id);
+                }
+            }
+        }
+        ans
+    }
+
+    /// Encode the edges that a replica at version `since` has not seen yet.
+    pub fn export_delta(&self, since: &evan::VersionVector) -> Vec<u8> {
+        self.algorithm.export_delta(since)
+    }
+
+    pub fn import_state(&mut self, bytes: &[u8]) -> Result<MergeReport<K>, DecodeError> {
+        let report = self.algorithm.import_state(bytes)?;
+        self.observe_version();
+        Ok(report)
+    }
+
+    /// Keep new op IDs above every edge write seen so far, as merging ops
+    /// does, so that local edits win over the edits they replace.
+    pub(crate) fn observe_version(&mut self) {
+        if let Some(&max) = self.algorithm.version().values().max() {
+            self.advance_to(max.saturating_add(1));
+        }
     }
 }
 
-impl<T: MovableTreeAlgorithm> ToString for MovableTree<T> {
-    fn to_string(&self) -> String {
+impl<K: NodeKey, T: MovableTreeAlgorithm<K>> Display for MovableTree<T, K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let root = self.algorithm.get_root();
-        root.to_string("".to_string(), true)
+        write!(f, "{}", root.to_string("".to_string(), true))
     }
 }
 
@@ -1,131 +1,366 @@
-use fxhash::FxHashMap;
+use fxhash::FxHashSet;
 
-use crate::{MovableTreeAlgorithm, NodeID, Op, TreeNode, TreeOp, ID, ROOT_ID};
+use crate::{
+    ancestor::AncestorIndex,
+    arena::{Arena, NIL},
+    MergeReport, MovableTreeAlgorithm, NodeID, NodeKey, Op, ParentCause, TreeNode, TreeOp, ID,
+};
 
-#[derive(Debug)]
-struct OpWrapper {
-    op: crate::Op,
-    old_parent: Option<NodeID>,
+/// Parent of a node that has been interned but is not in the tree, because
+/// the op that created it was reverted.
+const ABSENT: u32 = NIL - 1;
+
+#[derive(Debug, Clone)]
+struct OpWrapper<K> {
+    op: Op<K>,
+    /// The arena index of the target
+    target: u32,
+    /// The parent of the target before the op
+    old_parent: u32,
+    outcome: Outcome,
+}
+
+/// What became of an op when it was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Applied,
+    /// A move skipped because it would create a cycle.
+    Cycle,
+    /// A move of a node that is not in the tree, or under one, because its
+    /// create is unknown or was left out. It does nothing either.
+    Missing,
 }
 
-#[derive(Debug)]
-pub struct MartinTree {
-    tree: FxHashMap<NodeID, Option<NodeID>>,
-    sorted_ops: Vec<OpWrapper>,
+impl<K> OpWrapper<K> {
+    fn effective(&self) -> bool {
+        self.outcome == Outcome::Applied
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MartinTree<K = NodeID> {
+    arena: Arena<K>,
+    /// The parent of every interned node. [`NIL`] for the root and for nodes
+    /// only known as the parent of another node so far.
+    parents: Vec<u32>,
+    /// Every op in ID order. A merge undoes every op from the first incoming
+    /// one on and applies them again, so it costs at least the length of that
+    /// tail whatever the container. A `Vec` makes taking the tail off a
+    /// truncation and putting it back a push, and lookups by ID a binary
+    /// search, which a tree or rope would only make slower.
+    sorted_ops: Vec<OpWrapper<K>>,
     applied_end: usize,
+    index: Option<AncestorIndex<u32>>,
 }
 
-impl Default for MartinTree {
+impl<K: NodeKey> Default for MartinTree<K> {
     fn default() -> Self {
-        let mut tree = FxHashMap::default();
-        tree.insert(ROOT_ID, None);
         Self {
-            tree,
+            arena: Arena::new(),
+            parents: vec![NIL],
             sorted_ops: Vec::new(),
             applied_end: 0,
+            index: None,
         }
     }
 }
 
-impl MartinTree {
-    fn mov(&mut self, target: NodeID, parent: NodeID) {
-        assert!(self.tree.contains_key(&target));
-        if self.is_ancestor_of(target, parent) {
-            return;
+impl<K: NodeKey> MartinTree<K> {
+    /// Keep a link-cut tree in sync with the parent map so that ancestor
+    /// checks take `O(log n)` instead of walking up the whole depth of the
+    /// tree. Worth enabling for deep trees. Queries of the index have to
+    /// restructure it, so moves, [`crate::MovableTree::is_ancestor_of`] and
+    /// [`MovableTreeAlgorithm::is_ancestor_of_mut`] use it, while the shared
+    /// [`MovableTreeAlgorithm::is_ancestor_of`] still walks the parents.
+    pub fn enable_ancestor_index(&mut self) {
+        if self.index.is_none() {
+            let mut index = AncestorIndex::new();
+            for node in 0..self.parents.len() as u32 {
+                index.set_parent(node, self.parent_of(node));
+            }
+            self.index = Some(index);
+        }
+    }
+
+    pub fn with_ancestor_index() -> Self {
+        let mut tree = Self::default();
+        tree.enable_ancestor_index();
+        tree
+    }
+
+    fn intern(&mut self, key: K) -> u32 {
+        let (idx, new) = self.arena.intern(key);
+        if new {
+            self.parents.push(ABSENT);
+        }
+        idx
+    }
+
+    fn parent_of(&self, node: u32) -> Option<u32> {
+        match self.parents[node as usize] {
+            NIL | ABSENT => None,
+            parent => Some(parent),
+        }
+    }
+
+    fn set_parent(&mut self, node: u32, parent: u32) {
+        self.parents[node as usize] = parent;
+        let parent = self.parent_of(node);
+        if let Some(index) = &mut self.index {
+            index.set_parent(node, parent);
         }
-        self.tree.insert(target, Some(parent));
     }
 
-    fn apply_pending_ops(&mut self) {
+    fn create(&mut self, node: u32, parent: u32) {
+        if self.parents[parent as usize] == ABSENT {
+            self.set_parent(parent, NIL);
+        }
+        self.set_parent(node, parent);
+    }
+
+    fn mov(&mut self, target: u32, parent: u32) -> Outcome {
+        if self.parents[target as usize] == ABSENT || self.parents[parent as usize] == ABSENT {
+            return Outcome::Missing;
+        }
+        if self.is_ancestor(target, parent) {
+            return Outcome::Cycle;
+        }
+        self.set_parent(target, parent);
+        Outcome::Applied
+    }
+
+    fn is_ancestor(&mut self, maybe_ancestor: u32, node: u32) -> bool {
+        match &mut self.index {
+            Some(index) => index.is_ancestor_of(maybe_ancestor, node),
+            None => self.walk_is_ancestor(maybe_ancestor, node),
+        }
+    }
+
+    fn walk_is_ancestor(&self, maybe_ancestor: u32, mut node: u32) -> bool {
+        if maybe_ancestor == node {
+            return true;
+        }
+        while let Some(parent) = self.parent_of(node) {
+            if parent == maybe_ancestor {
+                return true;
+            }
+            if parent == node {
+                panic!("loop detected");
+            }
+            node = parent;
+        }
+        false
+    }
+
+    /// Returns the arena index of the target, its parent before the op and
+    /// what became of the op.
+    fn apply_op(&mut self, op: TreeOp<K>) -> (u32, u32, Outcome) {
+        let target = self.intern(op.target());
+        let parent = self.intern(op.parent());
+        let old_parent = self.parents[target as usize];
+        let outcome = match op {
+            TreeOp::Create { .. } if old_parent == ABSENT || old_parent == NIL => {
+                self.create(target, parent);
+                Outcome::Applied
+            }
+            // A node can be created more than once when peers concurrently
+            // create the same key. Every create after the first one is a move.
+            TreeOp::Create { .. } | TreeOp::Move { .. } => self.mov(target, parent),
+            // There are no edges to remove in this algorithm.
+            TreeOp::RemoveEdge { .. } => Outcome::Applied,
+        };
+        (target, old_parent, outcome)
+    }
+
+    /// Returns the ids of the ops skipped because they would create a cycle.
+    fn apply_pending_ops(&mut self) -> Vec<ID> {
+        let mut skipped = Vec::new();
         for i in self.applied_end..self.sorted_ops.len() {
-            let OpWrapper { op, old_parent } = &mut self.sorted_ops[i];
-            match op.op {
-                TreeOp::Create { parent } => {
-                    self.tree.entry(parent).or_insert(None);
-                    self.tree.insert(op.id.into(), Some(parent));
-                }
-                TreeOp::Move { target, parent, .. } => {
-                    *old_parent = self.tree.get(&target).copied().flatten();
-                    self.mov(target, parent);
-                }
+            let Op { id, op, .. } = self.sorted_ops[i].op;
+            let (target, old_parent, outcome) = self.apply_op(op);
+            self.sorted_ops[i].target = target;
+            self.sorted_ops[i].old_parent = old_parent;
+            self.sorted_ops[i].outcome = outcome;
+            if outcome == Outcome::Cycle {
+                skipped.push(id);
             }
         }
 
         self.applied_end = self.sorted_ops.len();
+        skipped
     }
 
-    fn revert_until(&mut self, id: &ID) -> Vec<Op> {
-        let trim_start = match self.sorted_ops.binary_search_by_key(&id, |x| &x.op.id) {
-            Ok(_) => unreachable!(),
-            Err(i) => i,
-        };
-        let ans: Vec<OpWrapper> = self.sorted_ops.drain(trim_start..).collect();
+    fn revert_until(&mut self, id: &ID) -> Vec<OpWrapper<K>> {
+        // An op can share its ID with ops already in the log if a peer sent
+        // several ops under one ID, which are then kept in log order.
+        let trim_start = self.sorted_ops.partition_point(|x| x.op.id < *id);
+        let ans: Vec<OpWrapper<K>> = self.sorted_ops.drain(trim_start..).collect();
         for op in ans.iter().rev() {
-            match op.op.op {
-                TreeOp::Create { .. } => {}
-                TreeOp::Move { target, .. } => {
-                    self.tree.insert(target, op.old_parent);
-                }
-            }
+            self.set_parent(op.target, op.old_parent);
         }
 
         self.applied_end = self.sorted_ops.len();
-        ans.into_iter().map(|x| x.op).collect()
+        ans
+    }
+
+    /// The target of the create or move op `id` and the parent the target had
+    /// right before it, as of the current order of the log. The parent is
+    /// `None` if the op created the node. Returns `None` if the op is not in
+    /// the log or was skipped because it would have created a cycle.
+    pub fn parent_before(&self, id: ID) -> Option<(K, Option<K>)> {
+        let i = self
+            .sorted_ops
+            .binary_search_by_key(&id, |x| x.op.id)
+            .ok()?;
+        let op = &self.sorted_ops[i];
+        if !op.effective() || matches!(op.op.op, TreeOp::RemoveEdge { .. }) {
+            return None;
+        }
+        let parent = match op.old_parent {
+            NIL | ABSENT => None,
+            parent => Some(self.arena.key(parent)),
+        };
+        Some((self.arena.key(op.target), parent))
     }
 
-    fn get_parent(&self, tree_id: NodeID) -> Option<NodeID> {
-        self.tree.get(&tree_id).copied().flatten()
+    fn get_parent(&self, tree_id: K) -> Option<K> {
+        let parent = self.parent_of(self.arena.get(&tree_id)?)?;
+        Some(self.arena.key(parent))
     }
 }
 
-impl MovableTreeAlgorithm for MartinTree {
+impl<K: NodeKey> MovableTreeAlgorithm<K> for MartinTree<K> {
     fn new() -> Self {
         Self::default()
     }
 
-    fn apply(&mut self, op: crate::Op, _local: bool) -> Vec<Op> {
-        let mut old_parent = None;
-        match op.op {
-            TreeOp::Create { parent } => {
-                self.tree.entry(parent).or_insert(None);
-                self.tree.insert(op.id.into(), Some(parent));
-            }
-            TreeOp::Move { target, parent, .. } => {
-                old_parent = self.tree.get(&target).copied().flatten();
-                self.mov(target, parent);
-            }
-        };
-        self.sorted_ops.push(OpWrapper { op, old_parent });
+    fn apply(&mut self, op: Op<K>, _local: bool) -> Vec<Op<K>> {
+        let (target, old_parent, outcome) = self.apply_op(op.op);
+        self.sorted_ops.push(OpWrapper {
+            op: op.clone(),
+            target,
+            old_parent,
+            outcome,
+        });
         self.applied_end = self.sorted_ops.len();
         vec![op]
     }
 
-    fn merge(&mut self, mut ops: Vec<crate::Op>) {
+    fn merge(&mut self, mut ops: Vec<Op<K>>) -> MergeReport<K> {
+        let mut report = MergeReport::default();
         if ops.is_empty() {
-            return;
+            return report;
         }
-        let start_id = ops.iter().min().unwrap();
-        let mut popped = self.revert_until(&start_id.id);
-        ops.append(&mut popped);
         ops.sort();
-        for op in ops {
+        // Fast path: every incoming op is newer than the whole log, so there
+        // is nothing to undo and the ops can be applied in order.
+        if self
+            .sorted_ops
+            .last()
+            .is_none_or(|last| last.op.id < ops[0].id)
+        {
+            for op in ops {
+                let id = op.id;
+                self.apply(op, false);
+                if self.sorted_ops.last().unwrap().outcome == Outcome::Cycle {
+                    report.cycle_skipped.push(id);
+                }
+            }
+            return report;
+        }
+
+        // Both the incoming ops and the reverted tail are already sorted, so
+        // interleave the two runs instead of sorting them together.
+        let popped = self.revert_until(&ops[0].id);
+        let already_skipped: FxHashSet<ID> = popped
+            .iter()
+            .filter(|x| x.outcome == Outcome::Cycle)
+            .map(|x| x.op.id)
+            .collect();
+        self.sorted_ops.reserve(ops.len() + popped.len());
+        let mut incoming = ops.into_iter().peekable();
+        let mut popped = popped.into_iter().map(|x| x.op).peekable();
+        loop {
+            let op = match (incoming.peek(), popped.peek()) {
+                (Some(a), Some(b)) if a < b => incoming.next(),
+                (Some(_), Some(_)) => popped.next(),
+                (Some(_), None) => incoming.next(),
+                (None, Some(_)) => popped.next(),
+                (None, None) => break,
+            };
             self.sorted_ops.push(OpWrapper {
-                op,
-                old_parent: None,
-            })
+                op: op.unwrap(),
+                target: NIL,
+                old_parent: NIL,
+                outcome: Outcome::Applied,
+            });
         }
-        self.apply_pending_ops();
+        report.cycle_skipped = self
+            .apply_pending_ops()
+            .into_iter()
+            .filter(|id| !already_skipped.contains(id))
+            .collect();
+        report
+    }
+
+    fn nodes(&self) -> Vec<K> {
+        (0..self.parents.len() as u32)
+            .filter(|&node| self.parents[node as usize] != ABSENT)
+            .map(|node| self.arena.key(node))
+            .collect()
     }
 
-    fn nodes(&self) -> Vec<NodeID> {
-        self.tree.keys().copied().collect()
+    fn contains(&self, node: K) -> bool {
+        self.arena
+            .get(&node)
+            .is_some_and(|node| self.parents[node as usize] != ABSENT)
     }
 
-    fn parent(&self, node: NodeID) -> Option<NodeID> {
+    fn parent(&self, node: K) -> Option<K> {
         self.get_parent(node)
     }
 
-    fn get_root(&self) -> crate::TreeNode {
-        TreeNode::from_state(&self.tree)
+    fn get_root(&self) -> TreeNode<K> {
+        self.arena.tree_node(|node| self.parent_of(node))
+    }
+
+    fn is_effective(&self, op: &Op<K>) -> bool {
+        match self.sorted_ops.binary_search_by_key(&op.id, |x| x.op.id) {
+            Ok(i) => self.sorted_ops[i].effective(),
+            Err(_) => false,
+        }
+    }
+
+    fn explain_parent(&self, node: K) -> Option<ParentCause<K>> {
+        self.get_parent(node)?;
+        self.sorted_ops
+            .iter()
+            .rev()
+            .find(|x| {
+                x.op.op.target() == node
+                    && x.effective()
+                    && !matches!(x.op.op, TreeOp::RemoveEdge { .. })
+            })
+            .map(|x| ParentCause::Op(x.op.id))
+    }
+
+    fn is_ancestor_of(&self, maybe_ancestor: K, node_id: K) -> bool {
+        if maybe_ancestor == node_id {
+            return true;
+        }
+        match (self.arena.get(&maybe_ancestor), self.arena.get(&node_id)) {
+            (Some(a), Some(b)) => self.walk_is_ancestor(a, b),
+            _ => false,
+        }
+    }
+
+    fn is_ancestor_of_mut(&mut self, maybe_ancestor: K, node_id: K) -> bool {
+        if maybe_ancestor == node_id {
+            return true;
+        }
+        match (self.arena.get(&maybe_ancestor), self.arena.get(&node_id)) {
+            (Some(a), Some(b)) => self.is_ancestor(a, b),
+            _ => false,
+        }
     }
 }
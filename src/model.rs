@@ -0,0 +1,293 @@
+//! Exhaustive checking of small scenarios.
+//!
+//! Random fuzzing rarely hits the one interleaving in a million where
+//! concurrent moves form a cycle in just the wrong order. [`check`] instead
+//! gives every peer a fixed script of moves and visits every state reachable
+//! by running the next move of some peer or syncing one peer into another,
+//! breadth first, so the first violation it finds has the fewest steps.
+//!
+//! In every state, each peer must satisfy [`check_invariants`], and must have
+//! the same tree as any other peer, or a fresh replica, that knows the same
+//! ops.
+
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{
+    analysis::Step, array_mut_ref, walk_is_ancestor_of, MovableTree, MovableTreeAlgorithm, NodeID,
+    TreeNode, ROOT_ID,
+};
+
+/// A scripted move. Nodes are numbered by creation order, and a `None`
+/// parent is the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    pub target: usize,
+    pub parent: Option<usize>,
+}
+
+/// Every move between `nodes` nodes that isn't trivially a cycle.
+pub fn moves(nodes: usize) -> Vec<Move> {
+    (0..nodes)
+        .flat_map(|target| {
+            std::iter::once(None)
+                .chain((0..nodes).map(Some))
+                .filter(move |&parent| parent != Some(target))
+                .map(move |parent| Move { target, parent })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    /// The steps from empty peers, including the creation of the nodes by
+    /// peer 0 and syncing them to everyone. Moves a peer rejected as cycles
+    /// are still listed.
+    pub steps: Vec<Step>,
+    pub violation: String,
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.violation)?;
+        for step in &self.steps {
+            match *step {
+                Step::Create { peer, parent } => {
+                    writeln!(f, "  peer {} creates a node under {}", peer, name(parent))?
+                }
+                Step::Move {
+                    peer,
+                    target,
+                    parent,
+                } => writeln!(f, "  peer {} moves {} under {}", peer, target, name(parent))?,
+                Step::Sync { from, to } => writeln!(f, "  peer {} merges peer {}", to, from)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn name(node: Option<usize>) -> String {
+    node.map_or("the root".to_string(), |n| n.to_string())
+}
+
+#[derive(Clone, Copy)]
+enum Choice {
+    Local(usize),
+    Sync { from: usize, to: usize },
+}
+
+/// Check every interleaving of `scripts`, one per peer, on `nodes` nodes
+/// created under the root. Returns the number of distinct states visited.
+///
+/// States are told apart by a 64 bit hash of the ops every peer knows, so a
+/// collision could in theory hide a state.
+pub fn check<T: MovableTreeAlgorithm + Clone>(
+    nodes: usize,
+    scripts: &[Vec<Move>],
+) -> Result<usize, Counterexample> {
+    check_with(nodes, scripts, T::new)
+}
+
+/// [`check`] with the trees of the peers made by `new`, such as
+/// [`crate::martin::MartinTree::with_ancestor_index`].
+pub fn check_with<T: MovableTreeAlgorithm + Clone>(
+    nodes: usize,
+    scripts: &[Vec<Move>],
+    new: impl Fn() -> T,
+) -> Result<usize, Counterexample> {
+    let peers = scripts.len();
+    let mut initial: Vec<MovableTree<T>> = (0..peers as u64)
+        .map(|peer| {
+            let mut tree = MovableTree::new(peer);
+            tree.algorithm = new();
+            tree
+        })
+        .collect();
+    let ids: Vec<NodeID> = (0..nodes).map(|_| initial[0].create(None)).collect();
+    for i in 1..peers {
+        let (a, b) = array_mut_ref!(&mut initial, [i, 0]);
+        a.merge(b);
+    }
+    let apply = |trees: &mut [MovableTree<T>], done: &mut [usize], choice| match choice {
+        Choice::Local(peer) => {
+            let Move { target, parent } = scripts[peer][done[peer]];
+            let parent = parent.map_or(ROOT_ID, |p| ids[p]);
+            // a move that would be a cycle here is rejected, like a UI would
+            let _ = trees[peer].mov(ids[target], parent);
+            done[peer] += 1;
+        }
+        Choice::Sync { from, to } => {
+            let (a, b) = array_mut_ref!(trees, [to, from]);
+            a.merge(b);
+        }
+    };
+
+    let mut visited = FxHashSet::default();
+    // the tree of every set of ops seen so far, by the hash of the ops
+    let mut expected = FxHashMap::default();
+    // Queue paths instead of states, as trees take much more memory and are
+    // quick to replay.
+    let mut queue = VecDeque::from([Vec::new()]);
+    while let Some(path) = queue.pop_front() {
+        let mut trees: Vec<_> = initial.iter().map(fork).collect();
+        let mut done = vec![0; peers];
+        for &choice in &path {
+            apply(&mut trees, &mut done, choice);
+        }
+
+        let mut choices: Vec<Choice> = (0..peers)
+            .filter(|&peer| done[peer] < scripts[peer].len())
+            .map(Choice::Local)
+            .collect();
+        for to in 0..peers {
+            for from in 0..peers {
+                if from != to && knows_more(&trees[from], &trees[to]) {
+                    choices.push(Choice::Sync { from, to });
+                }
+            }
+        }
+        for choice in choices {
+            let mut next_trees: Vec<_> = trees.iter().map(fork).collect();
+            let mut next_done = done.clone();
+            apply(&mut next_trees, &mut next_done, choice);
+            let ops: Vec<_> = next_trees.iter().map(|t| t.export()).collect();
+            if !visited.insert(hash(&(&next_done, &ops))) {
+                continue;
+            }
+            let mut next = path.clone();
+            next.push(choice);
+            for (peer, (tree, ops)) in next_trees.iter_mut().zip(ops).enumerate() {
+                if let Err(violation) = check_peer(tree, &ops, &mut expected) {
+                    return Err(Counterexample {
+                        steps: steps(nodes, scripts, &next),
+                        violation: format!("peer {}: {}", peer, violation),
+                    });
+                }
+            }
+            queue.push_back(next);
+        }
+    }
+    Ok(visited.len() + 1)
+}
+
+fn hash<H: Hash>(value: &H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether `a` has ops `b` doesn't. Op logs are always prefixes of the log of
+/// their peer, so comparing lengths is enough.
+fn knows_more<T>(a: &MovableTree<T>, b: &MovableTree<T>) -> bool {
+    a.ops
+        .iter()
+        .any(|(peer, ops)| ops.len() > b.ops.get(peer).map_or(0, |v| v.len()))
+}
+
+/// Copy a replica, which never has a clock here.
+fn fork<T: Clone>(tree: &MovableTree<T>) -> MovableTree<T> {
+    MovableTree {
+        algorithm: tree.algorithm.clone(),
+        peer: tree.peer,
+        ops: tree.ops.clone(),
+        next_lamport: tree.next_lamport,
+        clock: None,
+        quarantined: tree.quarantined.clone(),
+    }
+}
+
+fn steps(nodes: usize, scripts: &[Vec<Move>], path: &[Choice]) -> Vec<Step> {
+    let mut steps: Vec<Step> = (0..nodes)
+        .map(|_| Step::Create {
+            peer: 0,
+            parent: None,
+        })
+        .collect();
+    steps.extend((1..scripts.len()).map(|to| Step::Sync { from: 0, to }));
+    let mut done = vec![0; scripts.len()];
+    for &choice in path {
+        steps.push(match choice {
+            Choice::Local(peer) => {
+                let Move { target, parent } = scripts[peer][done[peer]];
+                done[peer] += 1;
+                Step::Move {
+                    peer,
+                    target,
+                    parent,
+                }
+            }
+            Choice::Sync { from, to } => Step::Sync { from, to },
+        });
+    }
+    steps
+}
+
+/// The first replica to know a set of ops is compared to a fresh replica that
+/// merges them all at once, and every later one to the first.
+fn check_peer<T: MovableTreeAlgorithm>(
+    tree: &mut MovableTree<T>,
+    ops: &[u8],
+    expected: &mut FxHashMap<u64, u64>,
+) -> Result<(), String> {
+    check_invariants(tree)?;
+    let s = hash(&tree.to_string());
+    match expected.get(&hash(&ops)) {
+        Some(e) if *e != s => {
+            Err("another peer with the same ops has a different tree".to_string())
+        }
+        Some(_) => Ok(()),
+        None => {
+            let mut fresh = MovableTree::<T>::new(u64::MAX - 1);
+            fresh.import(ops).unwrap();
+            if hash(&fresh.to_string()) != s {
+                return Err(
+                    "a replica that merges the same ops at once has a different tree".to_string(),
+                );
+            }
+            expected.insert(hash(&ops), s);
+            Ok(())
+        }
+    }
+}
+
+/// Check that the tree is well formed: every node has a parent, following
+/// parents always reaches the root, [`MovableTree::is_ancestor_of`], which
+/// uses the ancestor index of a tree that has one, agrees with walking the
+/// parents and [`MovableTreeAlgorithm::get_root`] agrees with
+/// [`MovableTreeAlgorithm::parent`].
+pub fn check_invariants<T: MovableTreeAlgorithm>(tree: &mut MovableTree<T>) -> Result<(), String> {
+    let nodes = tree.nodes();
+    let mut state = FxHashMap::default();
+    for &node in &nodes {
+        let mut current = node;
+        for _ in 0..=nodes.len() {
+            match tree.algorithm.parent(current) {
+                Some(ROOT_ID) => break,
+                Some(parent) if nodes.contains(&parent) => current = parent,
+                parent => return Err(format!("{} has parent {:?}", current, parent)),
+            }
+        }
+        if tree.algorithm.parent(current) != Some(ROOT_ID) {
+            return Err(format!("{} is in a cycle", node));
+        }
+        state.insert(node, tree.algorithm.parent(node));
+    }
+    let all: Vec<_> = nodes.iter().copied().chain([ROOT_ID]).collect();
+    for &a in &all {
+        for &b in &all {
+            if tree.is_ancestor_of(a, b) != walk_is_ancestor_of(&tree.algorithm, a, b) {
+                return Err(format!("wrong ancestor check of {} and {}", a, b));
+            }
+        }
+    }
+    if tree.algorithm.get_root() != TreeNode::from_state(&state) {
+        return Err("get_root doesn't match the parents".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Conversion between the edges of an [`EvanTree`] and the rows of the
+//! last-writer-wins database in `js/p2pdb.js`, which `js/index.js` stores
+//! its edges in. This lets a session recorded in the JS demo be replayed
+//! here, and a tree built here be fed to the JS viewer with `db.apply`.
+//!
+//! A row is one write of one edge: `id` is the child, `key` the parent and
+//! `value` the counter, or `undefined` once the edge has been removed. Rows
+//! of the same edge are ordered by `timestamp`, then by `peer`, which map to
+//! the lamport and peer of an [`EdgeCounter`].
+//!
+//! The JS code breaks ties between edges and between peers by comparing their
+//! IDs as strings. To keep the same tie-breaks here, node and peer names are
+//! packed into the bytes of the [`NodeID`] and peer ID, which then compare
+//! like the names do. Names that don't fit, at most 16 bytes for a node and
+//! 8 bytes for a peer, can't be imported. IDs that aren't packed names are
+//! exported as fixed width hex instead.
+//!
+//! The JS code also orders those IDs differently when breaking ties: the
+//! root sorts first instead of last, and a rescued node goes to the smallest
+//! parent instead of the largest. A tree only does the same after
+//! [`EvanTree::use_js_ties`], which changes how it resolves some conflicts,
+//! so every replica of a document has to agree on it.
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoding::{DecodeError, EdgeEntry, EdgeState},
+    evan::{EdgeCounter, EvanTree, VersionVector},
+    MergeReport, MovableTree, NodeID, MAX_LAMPORT, ROOT_ID,
+};
+
+/// The ID of the root node in `js/index.js`.
+pub const ROOT_NAME: &str = "(ROOT)";
+
+/// A row of `js/p2pdb.js`, as passed to `db.apply`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Row {
+    pub id: String,
+    pub key: String,
+    /// `None` for a removed edge, which JS leaves out of the row.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<u32>,
+    pub peer: String,
+    pub timestamp: u64,
+}
+
+pub fn node_name(id: NodeID) -> String {
+    if id == ROOT_ID {
+        return ROOT_NAME.to_string();
+    }
+    let bytes = ((id.lamport as u128) << 64 | id.peer as u128).to_be_bytes();
+    match unpack(&bytes) {
+        Some(name) if name != ROOT_NAME => name,
+        _ => format!("{:016x}{:016x}", id.lamport, id.peer),
+    }
+}
+
+pub fn parse_node_name(name: &str) -> Option<NodeID> {
+    if name == ROOT_NAME {
+        return Some(ROOT_ID);
+    }
+    let id = match name.len() {
+        32 => u128::from_str_radix(name, 16).ok()?,
+        _ => u128::from_be_bytes(pack(name)?),
+    };
+    let id = NodeID {
+        lamport: (id >> 64) as u64,
+        peer: id as u64,
+    };
+    (id != ROOT_ID).then_some(id)
+}
+
+pub fn peer_name(peer: u64) -> String {
+    unpack(&peer.to_be_bytes()).unwrap_or_else(|| format!("{:016x}", peer))
+}
+
+pub fn parse_peer_name(name: &str) -> Option<u64> {
+    let peer = match name.len() {
+        16 => u64::from_str_radix(name, 16).ok()?,
+        _ => u64::from_be_bytes(pack(name)?),
+    };
+    (peer != ROOT_ID.peer).then_some(peer)
+}
+
+/// Pads the name with zeros, so that a name sorts before any name it is a
+/// prefix of.
+fn pack<const N: usize>(name: &str) -> Option<[u8; N]> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > N || bytes.contains(&0) {
+        return None;
+    }
+    let mut packed = [0; N];
+    packed[..bytes.len()].copy_from_slice(bytes);
+    Some(packed)
+}
+
+fn unpack(bytes: &[u8]) -> Option<String> {
+    let len = bytes.iter().rposition(|&b| b != 0)? + 1;
+    let bytes = &bytes[..len];
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+pub fn encode_rows(edges: &[EdgeEntry<NodeID>]) -> Vec<Row> {
+    let mut edges = edges.to_vec();
+    edges.sort_by_key(|e| (e.child, e.parent));
+    edges
+        .iter()
+        .map(|edge| Row {
+            id: node_name(edge.child),
+            key: node_name(edge.parent),
+            value: (!edge.removed).then_some(edge.value.counter),
+            peer: peer_name(edge.value.peer),
+            timestamp: edge.value.lamport,
+        })
+        .collect()
+}
+
+/// The rows may contain several writes of the same edge, as a recorded
+/// session does. The version vector holds the largest timestamp of each peer.
+pub fn decode_rows(rows: &[Row]) -> Result<EdgeState<NodeID>, DecodeError> {
+    let mut version = VersionVector::default();
+    let mut edges = Vec::with_capacity(rows.len());
+    for row in rows {
+        let child = parse_node_name(&row.id).ok_or(DecodeError::Invalid("node name"))?;
+        let parent = parse_node_name(&row.key).ok_or(DecodeError::Invalid("node name"))?;
+        let peer = parse_peer_name(&row.peer).ok_or(DecodeError::Invalid("peer name"))?;
+        if child == ROOT_ID {
+            return Err(DecodeError::Invalid("edge of the root"));
+        }
+        if row.timestamp > MAX_LAMPORT {
+            return Err(DecodeError::Invalid("timestamp"));
+        }
+        let seen = version.entry(peer).or_insert(row.timestamp);
+        *seen = (*seen).max(row.timestamp);
+        edges.push(EdgeEntry {
+            child,
+            parent,
+            value: EdgeCounter {
+                counter: row.value.unwrap_or(0),
+                lamport: row.timestamp,
+                peer,
+            },
+            removed: row.value.is_none(),
+        });
+    }
+    Ok(EdgeState { version, edges })
+}
+
+impl EvanTree<NodeID> {
+    /// Break ties between edges the way `js/index.js` does, to end up with
+    /// the same tree as the JS demo for the same rows. Trees that don't call
+    /// this keep the tie-breaks of this crate and can resolve conflicts
+    /// differently, so they must not sync with trees that do.
+    pub fn use_js_ties(&mut self) {
+        self.set_js_ties(true);
+    }
+
+    /// One row for the latest write of every edge, including removed ones.
+    pub fn export_rows(&self) -> Vec<Row> {
+        encode_rows(&self.edges_since(&VersionVector::default()))
+    }
+
+    pub fn import_rows(&mut self, rows: &[Row]) -> Result<MergeReport, DecodeError> {
+        Ok(self.merge_edges(decode_rows(rows)?))
+    }
+}
+
+impl MovableTree<EvanTree> {
+    pub fn export_rows(&self) -> Vec<Row> {
+        self.algorithm.export_rows()
+    }
+
+    pub fn import_rows(&mut self, rows: &[Row]) -> Result<MergeReport, DecodeError> {
+        let report = self.algorithm.import_rows(rows)?;
+        self.observe_version();
+        Ok(report)
+    }
+}
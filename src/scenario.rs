@@ -0,0 +1,401 @@
+//! A small language for writing scenarios without the boilerplate of
+//! creating peers and comparing trees by hand:
+//!
+//! ```text
+//! peer A; peer B
+//! A: create x under root
+//! A: create y
+//! sync A B
+//! A: move x under y
+//! B: move y under x
+//! sync all
+//! expect:
+//!   y
+//!     x
+//! ```
+//!
+//! Statements are separated by newlines or `;`, and `#` starts a comment.
+//!
+//! - `peer A` adds a peer. Peers get IDs in the order they are added.
+//! - `A: create x under y` creates a node named `x` under `y`. Without
+//!   `under`, or with `under root`, the node is created under the root.
+//! - `A: move x under y` moves `x` under `y`, which fails if `A` would reject
+//!   the move as a cycle.
+//! - `sync A B ...` makes every listed peer merge every other one, and `sync
+//!   all` does the same for all peers.
+//! - `expect:` is followed by the expected tree without the root, on lines
+//!   indented deeper than `expect` and one more level for every level of the
+//!   tree. It checks every peer,
+//!   while `expect A:` only checks `A`. The order of siblings doesn't matter.
+
+use std::fmt::Display;
+
+use fxhash::FxHashMap;
+
+use crate::{MovableTree, MovableTreeAlgorithm, NodeID, ROOT_ID};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// 1-based line in the source.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Statement {
+    Peer(String),
+    Create {
+        peer: String,
+        node: String,
+        parent: Option<String>,
+    },
+    Move {
+        peer: String,
+        node: String,
+        parent: Option<String>,
+    },
+    /// `None` for all peers.
+    Sync(Option<Vec<String>>),
+    Expect {
+        peer: Option<String>,
+        /// The tree as [`render`] prints it.
+        tree: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    statements: Vec<(usize, Statement)>,
+}
+
+pub fn parse(src: &str) -> Result<Scenario, Error> {
+    let lines: Vec<&str> = src.lines().collect();
+    let mut statements = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = i + 1;
+        let text = strip_comment(lines[i]);
+        i += 1;
+        for text in text.split(';') {
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let err = |message: &str| Error {
+                line,
+                message: format!("{}: `{}`", message, text.trim()),
+            };
+            let statement = match words.as_slice() {
+                [] => continue,
+                ["peer", name] => Statement::Peer(name_of(name).ok_or_else(|| err("bad name"))?),
+                ["sync", "all"] => Statement::Sync(None),
+                ["sync", peers @ ..] if peers.len() >= 2 => Statement::Sync(Some(
+                    peers
+                        .iter()
+                        .map(|p| name_of(p).ok_or_else(|| err("bad name")))
+                        .collect::<Result<_, _>>()?,
+                )),
+                ["expect:"] | ["expect", _] => {
+                    let peer = match words[..] {
+                        ["expect", peer] => Some(
+                            peer.strip_suffix(':')
+                                .and_then(name_of)
+                                .ok_or_else(|| err("expected `expect PEER:`"))?,
+                        ),
+                        _ => None,
+                    };
+                    if text.trim() != strip_comment(lines[line - 1]).trim() {
+                        return Err(err("`expect` must be on its own line"));
+                    }
+                    // the tree is every following line indented deeper
+                    let indent = |line: &str| line.len() - line.trim_start().len();
+                    let depth = indent(lines[line - 1]);
+                    let start = i;
+                    while i < lines.len()
+                        && !strip_comment(lines[i]).trim().is_empty()
+                        && indent(lines[i]) > depth
+                    {
+                        i += 1;
+                    }
+                    let tree = parse_tree(&lines[start..i], start + 1)?;
+                    Statement::Expect { peer, tree }
+                }
+                [peer, op @ ("create" | "move"), node, rest @ ..] => {
+                    let peer = peer
+                        .strip_suffix(':')
+                        .and_then(name_of)
+                        .ok_or_else(|| err("expected `PEER:`"))?;
+                    let node = name_of(node).ok_or_else(|| err("bad name"))?;
+                    let parent = match rest {
+                        [] if *op == "create" => None,
+                        ["under", "root"] => None,
+                        ["under", parent] => Some(name_of(parent).ok_or_else(|| err("bad name"))?),
+                        _ => return Err(err("expected `under NODE`")),
+                    };
+                    match *op {
+                        "create" => Statement::Create { peer, node, parent },
+                        _ => Statement::Move { peer, node, parent },
+                    }
+                }
+                _ => return Err(err("unknown statement")),
+            };
+            statements.push((line, statement));
+        }
+    }
+    Ok(Scenario { statements })
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split('#').next().unwrap()
+}
+
+fn name_of(word: &str) -> Option<String> {
+    let valid = word != "root"
+        && word != "all"
+        && !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then(|| word.to_string())
+}
+
+/// Parse an indented tree into the form [`render`] prints.
+fn parse_tree(lines: &[&str], first_line: usize) -> Result<String, Error> {
+    let mut parents: Vec<(String, Option<String>)> = Vec::new();
+    // the indentation and name of the current node of every level
+    let mut stack: Vec<(usize, String)> = Vec::new();
+    for (i, text) in lines.iter().enumerate() {
+        let err = |message: &str| Error {
+            line: first_line + i,
+            message: format!("{}: `{}`", message, text.trim()),
+        };
+        let text = strip_comment(text).trim_end();
+        let indent = text.len() - text.trim_start().len();
+        let name = name_of(text.trim()).ok_or_else(|| err("bad name"))?;
+        if stack.first().is_some_and(|(base, _)| indent < *base) {
+            return Err(err("less indented than the first node"));
+        }
+        while stack.last().is_some_and(|(i, _)| *i >= indent) {
+            stack.pop();
+        }
+        if parents.iter().any(|(n, _)| *n == name) {
+            return Err(err("node listed twice"));
+        }
+        parents.push((name.clone(), stack.last().map(|(_, n)| n.clone())));
+        stack.push((indent, name));
+    }
+    Ok(render(&parents))
+}
+
+/// Print the tree given by the parent of every node, two spaces deeper per
+/// level and siblings sorted by name.
+fn render(parents: &[(String, Option<String>)]) -> String {
+    fn walk(
+        node: Option<&String>,
+        depth: usize,
+        parents: &[(String, Option<String>)],
+        s: &mut String,
+    ) {
+        let mut children: Vec<&String> = parents
+            .iter()
+            .filter(|(_, p)| p.as_ref() == node)
+            .map(|(n, _)| n)
+            .collect();
+        children.sort();
+        for child in children {
+            s.push_str(&"  ".repeat(depth));
+            s.push_str(child);
+            s.push('\n');
+            walk(Some(child), depth + 1, parents, s);
+        }
+    }
+
+    let mut s = String::new();
+    walk(None, 0, parents, &mut s);
+    s
+}
+
+impl Scenario {
+    /// Run the scenario, stopping at the first statement that fails.
+    pub fn run<T: MovableTreeAlgorithm>(&self) -> Result<(), Error> {
+        let mut peers: Vec<(String, MovableTree<T>)> = Vec::new();
+        let mut nodes: FxHashMap<String, NodeID> = FxHashMap::default();
+        for (line, statement) in &self.statements {
+            let err = |message: String| Error {
+                line: *line,
+                message,
+            };
+            let peer_index = |name: &String| {
+                peers
+                    .iter()
+                    .position(|(n, _)| n == name)
+                    .ok_or_else(|| err(format!("unknown peer {}", name)))
+            };
+            let node = |name: &Option<String>| match name {
+                None => Ok(ROOT_ID),
+                Some(name) => nodes
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| err(format!("unknown node {}", name))),
+            };
+            match statement {
+                Statement::Peer(name) => {
+                    if peer_index(name).is_ok() {
+                        return Err(err(format!("peer {} already exists", name)));
+                    }
+                    let tree = MovableTree::new(peers.len() as u64);
+                    peers.push((name.clone(), tree));
+                }
+                Statement::Create {
+                    peer,
+                    node: n,
+                    parent,
+                } => {
+                    let i = peer_index(peer)?;
+                    let parent = node(parent)?;
+                    if nodes.contains_key(n) {
+                        return Err(err(format!("node {} already exists", n)));
+                    }
+                    let tree = &mut peers[i].1;
+                    if parent != ROOT_ID && tree.algorithm.parent(parent).is_none() {
+                        return Err(err(format!(
+                            "{} doesn't know node {}",
+                            peer,
+                            name(&nodes, parent)
+                        )));
+                    }
+                    let id = tree.create(Some(parent));
+                    nodes.insert(n.clone(), id);
+                }
+                Statement::Move {
+                    peer,
+                    node: n,
+                    parent,
+                } => {
+                    let i = peer_index(peer)?;
+                    let target = node(&Some(n.clone()))?;
+                    let parent = node(parent)?;
+                    let tree = &mut peers[i].1;
+                    for id in [target, parent] {
+                        if id != ROOT_ID && tree.algorithm.parent(id).is_none() {
+                            return Err(err(format!(
+                                "{} doesn't know node {}",
+                                peer,
+                                name(&nodes, id)
+                            )));
+                        }
+                    }
+                    tree.mov(target, parent).map_err(|_| {
+                        err(format!(
+                            "{} rejects moving {} under {} as a cycle",
+                            peer,
+                            n,
+                            name(&nodes, parent)
+                        ))
+                    })?;
+                }
+                Statement::Sync(names) => {
+                    let indices: Vec<usize> = match names {
+                        None => (0..peers.len()).collect(),
+                        Some(names) => names.iter().map(peer_index).collect::<Result<_, _>>()?,
+                    };
+                    // the first peer merges everyone else before they merge it
+                    for &a in &indices {
+                        for &b in &indices {
+                            if a != b {
+                                let (x, y) = crate::array_mut_ref!(&mut peers, [a, b]);
+                                x.1.merge(&y.1);
+                            }
+                        }
+                    }
+                }
+                Statement::Expect { peer, tree } => {
+                    let checked: Vec<usize> = match peer {
+                        None => (0..peers.len()).collect(),
+                        Some(peer) => vec![peer_index(peer)?],
+                    };
+                    for i in checked {
+                        let (peer, actual) = (&peers[i].0, &peers[i].1);
+                        let actual = render_tree(actual, &nodes);
+                        if actual != *tree {
+                            return Err(err(format!(
+                                "tree of {} doesn't match, - expected + actual:\n{}",
+                                peer,
+                                diff(tree, &actual)
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse and run `src` in one go.
+pub fn run<T: MovableTreeAlgorithm>(src: &str) -> Result<(), Error> {
+    parse(src)?.run::<T>()
+}
+
+fn name(nodes: &FxHashMap<String, NodeID>, id: NodeID) -> String {
+    if id == ROOT_ID {
+        return "root".to_string();
+    }
+    nodes
+        .iter()
+        .find(|(_, &n)| n == id)
+        .map_or_else(|| id.to_string(), |(name, _)| name.clone())
+}
+
+fn render_tree<T: MovableTreeAlgorithm>(
+    tree: &MovableTree<T>,
+    nodes: &FxHashMap<String, NodeID>,
+) -> String {
+    let parents: Vec<_> = tree
+        .nodes()
+        .into_iter()
+        .map(|id| {
+            let parent = tree.algorithm.parent(id).filter(|&p| p != ROOT_ID);
+            (name(nodes, id), parent.map(|p| name(nodes, p)))
+        })
+        .collect();
+    render(&parents)
+}
+
+/// A line diff, with `-` for lines only in `expected` and `+` for lines only
+/// in `actual`.
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // longest common subsequence of every pair of suffixes
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = match a[i] == b[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut s = String::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            s += &format!("  {}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            s += &format!("- {}\n", a[i]);
+            i += 1;
+        } else {
+            s += &format!("+ {}\n", b[j]);
+            j += 1;
+        }
+    }
+    s
+}
@@ -0,0 +1,290 @@
+//! A deterministic network of peers for testing convergence.
+//!
+//! Every tick, peers make edits chosen by a workload and send their op logs
+//! to each other. Messages have a random latency, so they can arrive out of
+//! order, and can be lost, duplicated or cut off by a partition. Everything
+//! comes from one seeded RNG. What actually happened is recorded as a
+//! [`Event`] schedule, which [`Sim::replay`] can run again without any RNG,
+//! even after the workload or the simulator have changed.
+//!
+//! A message holds the whole op log of the sender, as
+//! [`MovableTree::export`] encodes it. Merging op logs is idempotent and
+//! commutative, so stale, repeated or reordered messages are harmless.
+
+use std::{cmp::Reverse, collections::BinaryHeap, ops::RangeInclusive};
+
+use fxhash::FxHashMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{MovableTree, MovableTreeAlgorithm, NodeID, ROOT_ID};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub peers: usize,
+    /// Probability that a peer asks the workload for an edit in a tick.
+    pub edit: f64,
+    /// Probability that a peer sends its op log to the other peers in a tick.
+    pub sync: f64,
+    /// Ticks a message takes to arrive, drawn uniformly from this range.
+    pub latency: RangeInclusive<u64>,
+    /// Probability that a message is lost.
+    pub loss: f64,
+    /// Probability that a message is delivered twice.
+    pub duplicate: f64,
+    /// Probability per tick that the peers split into two groups that can't
+    /// reach each other, if they are not split already.
+    pub partition: f64,
+    /// Probability per tick that a partition heals.
+    pub heal: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            peers: 3,
+            edit: 0.5,
+            sync: 0.2,
+            latency: 1..=5,
+            loss: 0.1,
+            duplicate: 0.05,
+            partition: 0.02,
+            heal: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    Create { parent: Option<NodeID> },
+    Move { target: NodeID, parent: NodeID },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// An edit the peer accepted.
+    Edit { peer: usize, edit: Edit },
+    /// `from` sends its current op log to `to`. Messages are numbered by the
+    /// order of their `Send` events.
+    Send { from: usize, to: usize },
+    /// A message arrives. Lost messages never do, and duplicated ones do
+    /// twice.
+    Deliver { message: usize },
+}
+
+pub struct Sim<T> {
+    pub peers: Vec<MovableTree<T>>,
+    config: Config,
+    seed: u64,
+    rng: StdRng,
+    tick: u64,
+    /// The sender, the receiver and the content of every message sent.
+    messages: Vec<(usize, usize, Vec<u8>)>,
+    /// Deliveries by tick, then by the order they were scheduled in.
+    in_flight: BinaryHeap<Reverse<(u64, usize, usize)>>,
+    scheduled: usize,
+    /// The group of every peer while the network is split.
+    partition: Option<Vec<bool>>,
+    schedule: Vec<Event>,
+}
+
+impl<T: MovableTreeAlgorithm> Sim<T> {
+    pub fn new(config: Config, seed: u64) -> Self {
+        Sim {
+            peers: (0..config.peers as u64).map(MovableTree::new).collect(),
+            config,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            tick: 0,
+            messages: Vec::new(),
+            in_flight: BinaryHeap::new(),
+            scheduled: 0,
+            partition: None,
+            schedule: Vec::new(),
+        }
+    }
+
+    pub fn schedule(&self) -> &[Event] {
+        &self.schedule
+    }
+
+    /// Run `schedule` on `peers` fresh peers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the schedule doesn't fit, like an edit the peer rejects.
+    pub fn replay(peers: usize, schedule: &[Event]) -> Self {
+        let mut sim = Self::new(
+            Config {
+                peers,
+                ..Default::default()
+            },
+            0,
+        );
+        for &event in schedule {
+            match event {
+                Event::Edit { peer, edit } => sim.apply(peer, edit).expect("edit rejected"),
+                Event::Send { from, to } => sim.send(from, to),
+                Event::Deliver { message } => sim.deliver(message),
+            }
+        }
+        sim
+    }
+
+    /// Make `edit` on `peer` and record it, unless the peer rejects it.
+    #[allow(clippy::result_unit_err)]
+    pub fn apply(&mut self, peer: usize, edit: Edit) -> Result<(), ()> {
+        let tree = &mut self.peers[peer];
+        match edit {
+            Edit::Create { parent } => {
+                tree.create(parent);
+            }
+            Edit::Move { target, parent } => tree.mov(target, parent)?,
+        }
+        self.schedule.push(Event::Edit { peer, edit });
+        Ok(())
+    }
+
+    /// Advance the network by one tick.
+    pub fn step<W>(&mut self, workload: &mut W)
+    where
+        W: FnMut(&mut StdRng, &MovableTree<T>) -> Option<Edit>,
+    {
+        self.tick += 1;
+        self.deliver_due();
+        match &self.partition {
+            None if self.rng.gen_bool(self.config.partition) => {
+                let groups = (0..self.peers.len()).map(|_| self.rng.gen()).collect();
+                self.partition = Some(groups);
+            }
+            Some(_) if self.rng.gen_bool(self.config.heal) => self.partition = None,
+            _ => {}
+        }
+        for peer in 0..self.peers.len() {
+            if self.rng.gen_bool(self.config.edit) {
+                if let Some(edit) = workload(&mut self.rng, &self.peers[peer]) {
+                    // a move the peer rejects as a cycle is just not made
+                    let _ = self.apply(peer, edit);
+                }
+            }
+            if self.rng.gen_bool(self.config.sync) {
+                self.broadcast(peer, true);
+            }
+        }
+    }
+
+    pub fn run<W>(&mut self, ticks: usize, workload: &mut W)
+    where
+        W: FnMut(&mut StdRng, &MovableTree<T>) -> Option<Edit>,
+    {
+        for _ in 0..ticks {
+            self.step(workload);
+        }
+    }
+
+    /// Heal the network and let every peer send its op log to every other
+    /// peer without loss, then deliver everything in flight.
+    pub fn settle(&mut self) {
+        self.partition = None;
+        for peer in 0..self.peers.len() {
+            self.broadcast(peer, false);
+        }
+        while !self.in_flight.is_empty() {
+            self.tick += 1;
+            self.deliver_due();
+        }
+    }
+
+    pub fn is_converged(&self) -> bool {
+        let first = parents(&self.peers[0]);
+        self.peers[1..].iter().all(|peer| parents(peer) == first)
+    }
+
+    /// # Panics
+    ///
+    /// Panics with the seed and the schedule if the peers have diverged.
+    pub fn assert_converged(&self) {
+        assert!(
+            self.is_converged(),
+            "peers diverged with seed {}, schedule: {:?}",
+            self.seed,
+            self.schedule
+        );
+    }
+
+    fn broadcast(&mut self, from: usize, lossy: bool) {
+        for to in 0..self.peers.len() {
+            if to == from || (lossy && self.rng.gen_bool(self.config.loss)) {
+                continue;
+            }
+            let copies = match lossy && self.rng.gen_bool(self.config.duplicate) {
+                true => 2,
+                false => 1,
+            };
+            self.send(from, to);
+            for _ in 0..copies {
+                let latency = self.rng.gen_range(self.config.latency.clone());
+                self.in_flight.push(Reverse((
+                    self.tick + latency,
+                    self.scheduled,
+                    self.messages.len() - 1,
+                )));
+                self.scheduled += 1;
+            }
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize) {
+        self.messages.push((from, to, self.peers[from].export()));
+        self.schedule.push(Event::Send { from, to });
+    }
+
+    fn deliver_due(&mut self) {
+        while let Some(&Reverse((tick, _, message))) = self.in_flight.peek() {
+            if tick > self.tick {
+                break;
+            }
+            self.in_flight.pop();
+            let (from, to, _) = self.messages[message];
+            match &self.partition {
+                Some(groups) if groups[from] != groups[to] => {}
+                _ => self.deliver(message),
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: usize) {
+        let (_, to, bytes) = &self.messages[message];
+        self.peers[*to].import(bytes).unwrap();
+        self.schedule.push(Event::Deliver { message });
+    }
+}
+
+fn parents<T: MovableTreeAlgorithm>(tree: &MovableTree<T>) -> FxHashMap<NodeID, Option<NodeID>> {
+    tree.nodes()
+        .into_iter()
+        .map(|node| (node, tree.algorithm.parent(node)))
+        .collect()
+}
+
+/// Create a node under a random node, or move a random node under another
+/// one, like a user clicking around would.
+pub fn random_edit<T: MovableTreeAlgorithm>(
+    rng: &mut StdRng,
+    tree: &MovableTree<T>,
+) -> Option<Edit> {
+    let mut nodes = tree.nodes();
+    nodes.sort();
+    let pick = |rng: &mut StdRng| match rng.gen_range(0..=nodes.len()) {
+        0 => ROOT_ID,
+        i => nodes[i - 1],
+    };
+    if nodes.len() < 2 || rng.gen_ratio(1, 4) {
+        let parent = pick(rng);
+        return Some(Edit::Create {
+            parent: Some(parent),
+        });
+    }
+    let target = nodes[rng.gen_range(0..nodes.len())];
+    let parent = pick(rng);
+    (!tree.algorithm.is_ancestor_of(target, parent)).then_some(Edit::Move { target, parent })
+}
@@ -1,4 +1,4 @@
-use movable_tree::{evan::EvanTree, martin::MartinTree, MovableTree};
+use movable_tree::{evan::EvanTree, martin::MartinTree, MovableTree, MovableTreeAlgorithm};
 
 #[test]
 fn tree() {
@@ -30,3 +30,1243 @@ fn tree2() {
     tree2.merge(&tree);
     assert_eq!(tree.to_string(), tree2.to_string());
 }
+
+#[test]
+fn copy_subtree() {
+    fn check<T: movable_tree::MovableTreeAlgorithm>() {
+        let mut tree = MovableTree::<T>::new(0);
+        let a = tree.create(None);
+        let b = tree.create(Some(a));
+        let c = tree.create(Some(b));
+        let dest = tree.create(None);
+        let mut tree2 = MovableTree::<T>::new(1);
+        tree2.merge(&tree);
+
+        // peer 1 reshapes the source while peer 0 copies it
+        tree2.mov(c, a).unwrap();
+        tree2.mov(b, dest).unwrap();
+        let (copy, mapping) = tree.copy_subtree(a, dest).unwrap();
+        assert_eq!(mapping.len(), 3);
+        assert_eq!(mapping[&a], copy);
+        assert_eq!(tree.algorithm.parent(copy), Some(dest));
+        assert_eq!(tree.algorithm.parent(mapping[&b]), Some(copy));
+        assert_eq!(tree.algorithm.parent(mapping[&c]), Some(mapping[&b]));
+
+        tree.merge(&tree2);
+        tree2.merge(&tree);
+        assert_eq!(tree.to_string(), tree2.to_string());
+        assert_eq!(tree.algorithm.parent(mapping[&b]), Some(copy));
+        assert_eq!(tree.algorithm.parent(mapping[&c]), Some(mapping[&b]));
+        assert!(tree.copy_subtree(movable_tree::ROOT_ID, dest).is_err());
+    }
+    check::<EvanTree>();
+    check::<MartinTree>();
+}
+
+#[test]
+fn ancestor_index_matches_parent_walk() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    let mut plain = [MovableTree::<MartinTree>::new(0), MovableTree::new(1)];
+    let mut indexed = [MovableTree::<MartinTree>::new(0), MovableTree::new(1)];
+    for tree in indexed.iter_mut() {
+        tree.algorithm.enable_ancestor_index();
+    }
+    let mut ids = vec![];
+    for _ in 0..30 {
+        ids.push(plain[0].create(None));
+        indexed[0].create(None);
+    }
+    let (a, b) = plain.split_at_mut(1);
+    b[0].merge(&a[0]);
+    let (a, b) = indexed.split_at_mut(1);
+    b[0].merge(&a[0]);
+    let mut rng = StdRng::seed_from_u64(42);
+    for t in 0..2000 {
+        let i = ids[rng.gen::<usize>() % ids.len()];
+        let j = ids[rng.gen::<usize>() % ids.len()];
+        let k = t % 2;
+        assert_eq!(
+            plain[k].is_ancestor_of(i, j),
+            indexed[k].is_ancestor_of(i, j)
+        );
+        plain[k].mov(i, j).unwrap_or_default();
+        indexed[k].mov(i, j).unwrap_or_default();
+        if rng.gen::<usize>() % 7 == 0 {
+            let (a, b) = plain.split_at_mut(1);
+            a[0].merge(&b[0]);
+            b[0].merge(&a[0]);
+            let (a, b) = indexed.split_at_mut(1);
+            a[0].merge(&b[0]);
+            b[0].merge(&a[0]);
+        }
+        assert_eq!(plain[k].to_string(), indexed[k].to_string());
+    }
+}
+
+#[test]
+fn evan_nodes() {
+    let mut tree = MovableTree::<EvanTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(Some(a));
+    let mut tree2 = MovableTree::<EvanTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap_err();
+    tree2.mov(b, movable_tree::ROOT_ID).unwrap();
+    tree.mov(a, b).unwrap_err();
+    tree2.mov(a, b).unwrap();
+    tree.merge(&tree2);
+
+    let node = tree.algorithm.get_node(a).unwrap();
+    assert_eq!(node.id(), a);
+    assert_eq!(node.parent(), Some(b));
+    assert_eq!(node.largest_edge(), Some(b));
+    assert_eq!(node.edges().len(), 2);
+    assert!(tree.algorithm.get_node(movable_tree::ROOT_ID).is_some());
+    assert_eq!(tree.algorithm.iter_nodes().count(), 3);
+}
+
+#[test]
+fn trees_are_send_and_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<MartinTree>();
+    check::<EvanTree>();
+    fn check_replica<T: Send>() {}
+    check_replica::<MovableTree<MartinTree>>();
+    check_replica::<MovableTree<EvanTree>>();
+}
+
+#[test]
+fn martin_merge_interleaved_runs() {
+    let mut a = MovableTree::<MartinTree>::new(0);
+    let nodes: Vec<_> = (0..4).map(|_| a.create(None)).collect();
+    let mut b = MovableTree::<MartinTree>::new(1);
+    let mut c = MovableTree::<MartinTree>::new(2);
+    b.merge(&a);
+    c.merge(&a);
+    // interleaved lamports on every peer, so merges have to redo the tail
+    for i in 0..4 {
+        a.mov(nodes[i], nodes[(i + 1) % 4]).unwrap_or_default();
+        b.mov(nodes[(i + 1) % 4], nodes[i]).unwrap_or_default();
+        c.mov(nodes[(i + 2) % 4], nodes[(i + 3) % 4])
+            .unwrap_or_default();
+    }
+    let mut order_1 = MovableTree::<MartinTree>::new(3);
+    order_1.merge(&a);
+    order_1.merge(&b);
+    order_1.merge(&c);
+    let mut order_2 = MovableTree::<MartinTree>::new(4);
+    order_2.merge(&c);
+    order_2.merge(&b);
+    order_2.merge(&a);
+    a.merge(&c);
+    a.merge(&b);
+    assert_eq!(order_1.to_string(), order_2.to_string());
+    assert_eq!(order_1.to_string(), a.to_string());
+}
+
+#[test]
+fn merge_report() {
+    // concurrent moves of the same node: peer 1 wins the tie on lamport
+    let mut tree = MovableTree::<MartinTree>::new(0);
+    let x = tree.create(None);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let mut tree2 = MovableTree::<MartinTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(x, a).unwrap();
+    tree2.mov(x, b).unwrap();
+    let report = tree.merge_with_report(&tree2);
+    assert_eq!(report.overridden.len(), 1);
+    assert_eq!(report.overridden[0].target, x);
+    assert_eq!(report.overridden[0].parent, a);
+    assert_eq!(report.overridden[0].current_parent, Some(b));
+    assert!(tree2.merge_with_report(&tree).overridden.is_empty());
+
+    // concurrent moves forming a cycle: the later one is skipped by Martin
+    let mut tree = MovableTree::<MartinTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let mut tree2 = MovableTree::<MartinTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap();
+    tree2.mov(b, a).unwrap();
+    let report = tree.merge_with_report(&tree2);
+    assert_eq!(report.cycle_skipped.len(), 1);
+    assert_eq!(report.cycle_skipped[0].peer, 1);
+    let report = tree2.merge_with_report(&tree);
+    assert_eq!(report.cycle_skipped.len(), 1);
+    assert!(report.overridden.is_empty());
+
+    // and rescued by Evan
+    let mut tree = MovableTree::<EvanTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let mut tree2 = MovableTree::<EvanTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap();
+    tree2.mov(b, a).unwrap();
+    let report = tree.merge_with_report(&tree2);
+    assert_eq!(report.rescued.len(), 1);
+    assert_eq!(
+        tree.algorithm.parent(report.rescued[0].0),
+        Some(report.rescued[0].1)
+    );
+    assert_eq!(report.cycle_skipped, vec![]);
+    // a later merge that only brings unrelated ops doesn't report it again
+    tree2.merge(&tree);
+    tree2.create(None);
+    assert!(tree.merge_with_report(&tree2).rescued.is_empty());
+    let mut tree3 = MovableTree::<EvanTree>::new(2);
+    tree3.merge(&tree2);
+    tree3.create(None);
+    assert!(tree.merge_state(&tree3).rescued.is_empty());
+}
+
+#[test]
+fn evan_rooting_edits_sync_to_their_own_node() {
+    let mut tree = MovableTree::<EvanTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let c = tree.create(None);
+    let mut tree2 = MovableTree::<EvanTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap();
+    tree2.mov(b, a).unwrap();
+    tree.merge(&tree2);
+    tree2.merge(&tree);
+    let rescued = if tree.algorithm.parent(a) == Some(movable_tree::ROOT_ID) {
+        a
+    } else {
+        b
+    };
+    // Moving c under the rescued node first roots it with an edit of its own
+    // edge, which remote peers have to write onto the rescued node too.
+    tree.mov(c, rescued).unwrap();
+    tree2.merge(&tree);
+    for node in [a, b, c] {
+        assert_eq!(tree.history(node), tree2.history(node));
+        assert_eq!(tree.explain_parent(node), tree2.explain_parent(node));
+    }
+}
+
+#[test]
+fn history_and_explain_parent() {
+    use movable_tree::ParentCause;
+    let mut tree = MovableTree::<MartinTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let mut tree2 = MovableTree::<MartinTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap();
+    tree2.mov(b, a).unwrap();
+    tree.merge(&tree2);
+
+    let history = tree.history(b);
+    assert_eq!(history.len(), 2);
+    assert!(history[0].create && history[0].effective);
+    assert_eq!(history[1].parent, a);
+    assert!(!history[1].effective);
+    // the cyclic move was skipped, so b is still where it was created
+    assert_eq!(tree.explain_parent(b), Some(ParentCause::Op(history[0].id)));
+    assert_eq!(
+        tree.explain_parent(a),
+        Some(ParentCause::Op(tree.history(a)[1].id))
+    );
+
+    let mut tree = MovableTree::<EvanTree>::new(0);
+    let a = tree.create(None);
+    let b = tree.create(None);
+    let mut tree2 = MovableTree::<EvanTree>::new(1);
+    tree2.merge(&tree);
+    tree.mov(a, b).unwrap();
+    tree2.mov(b, a).unwrap();
+    tree.merge(&tree2);
+    let history = tree.history(b);
+    assert_eq!(history.len(), 2);
+    assert!(history.iter().all(|r| r.effective));
+    let rescued = [a, b]
+        .into_iter()
+        .filter(|&n| {
+            matches!(
+                tree.explain_parent(n),
+                Some(ParentCause::Edge { rescued: true, .. })
+            )
+        })
+        .count();
+    assert_eq!(rescued, 1);
+}
+
+#[test]
+fn op_meta_travels_with_ops() {
+    use movable_tree::OpMeta;
+    fn check<T: MovableTreeAlgorithm>() {
+        let mut tree = MovableTree::<T>::new(0);
+        let meta = OpMeta {
+            author: Some("alice".into()),
+            timestamp: Some(1_700_000_000_000),
+            message: b"create inbox".to_vec(),
+        };
+        let a = tree.create_with_meta(None, meta.clone());
+        let b = tree.create(None);
+        let mut tree2 = MovableTree::<T>::new(1);
+        tree2.merge(&tree);
+        let move_meta = OpMeta {
+            author: Some("bob".into()),
+            ..Default::default()
+        };
+        tree2.mov_with_meta(a, b, move_meta.clone()).unwrap();
+        tree.merge(&tree2);
+
+        let history = tree.history(a);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].meta.as_deref(), Some(&meta));
+        assert_eq!(history[1].meta.as_deref(), Some(&move_meta));
+        assert_eq!(tree.history(b)[0].meta, None);
+    }
+    check::<EvanTree>();
+    check::<MartinTree>();
+}
+
+#[test]
+fn hybrid_logical_clock() {
+    use movable_tree::clock::Clock;
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    #[derive(Clone)]
+    struct ManualClock(Arc<AtomicU64>);
+    impl Clock for ManualClock {
+        fn now(&mut self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    fn run(with_clock: bool) -> (MovableTree<MartinTree>, [movable_tree::NodeID; 3]) {
+        let time = ManualClock(Arc::new(AtomicU64::new(10)));
+        let (mut busy, mut offline) = if with_clock {
+            (
+                MovableTree::<MartinTree>::with_clock(0, time.clone()),
+                MovableTree::<MartinTree>::with_clock(1, time.clone()),
+            )
+        } else {
+            (MovableTree::new(0), MovableTree::new(1))
+        };
+        let x = busy.create(None);
+        let a = busy.create(None);
+        let b = busy.create(None);
+        offline.merge(&busy);
+        // peer 1 makes a lot of edits, moves x and goes offline; peer 0
+        // moves x much later in real time
+        for _ in 0..100 {
+            offline.create(None);
+        }
+        offline.mov(x, a).unwrap();
+        time.0.store(1000, Ordering::Relaxed);
+        busy.mov(x, b).unwrap();
+        busy.merge(&offline);
+        (busy, [x, a, b])
+    }
+
+    let (tree, [x, a, _]) = run(false);
+    assert_eq!(tree.algorithm.parent(x), Some(a));
+    let (tree, [x, _, b]) = run(true);
+    assert_eq!(tree.algorithm.parent(x), Some(b));
+
+    // a burst of edits within one tick only advances the logical counter,
+    // also on the peers it is relayed to, so an edit made a tick later on a
+    // peer that never saw the burst still wins
+    let time = ManualClock(Arc::new(AtomicU64::new(10)));
+    let mut busy = MovableTree::<MartinTree>::with_clock(0, time.clone());
+    let mut relay = MovableTree::<MartinTree>::with_clock(1, time.clone());
+    let mut late = MovableTree::<MartinTree>::with_clock(2, time.clone());
+    let [x, a, b, c] = [(); 4].map(|_| busy.create(None));
+    late.merge(&busy);
+    for _ in 0..1000 {
+        busy.create(None);
+    }
+    busy.mov(x, a).unwrap();
+    relay.merge(&busy);
+    time.0.store(11, Ordering::Relaxed);
+    relay.mov(x, c).unwrap();
+    time.0.store(12, Ordering::Relaxed);
+    late.mov(x, b).unwrap();
+    busy.merge(&relay);
+    busy.merge(&late);
+    assert_eq!(busy.algorithm.parent(x), Some(b));
+}
+
+#[test]
+fn export_import() {
+    use movable_tree::{encoding::DecodeError, OpMeta};
+    fn check<T: MovableTreeAlgorithm>() {
+        let mut tree = MovableTree::<T>::new(0);
+        let a = tree.create(None);
+        let b = tree.create_with_meta(
+            Some(a),
+            OpMeta {
+                author: Some("alice".into()),
+                timestamp: Some(42),
+                message: b"hi".to_vec(),
+            },
+        );
+        let mut tree2 = MovableTree::<T>::new(u64::MAX - 1);
+        tree2.import(&tree.export()).unwrap();
+        tree2.mov(b, movable_tree::ROOT_ID).unwrap();
+        tree2.mov(a, b).unwrap();
+
+        tree.import(&tree2.export()).unwrap();
+        assert_eq!(tree.to_string(), tree2.to_string());
+        assert_eq!(tree.history(b), tree2.history(b));
+        assert_eq!(tree.export(), tree2.export());
+
+        let bytes = tree.export();
+        assert_eq!(
+            tree.import(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::UnexpectedEnd)
+        );
+        assert_eq!(tree.import(&[9]), Err(DecodeError::UnsupportedVersion(9)));
+    }
+    check::<EvanTree>();
+    check::<MartinTree>();
+}
+
+#[test]
+fn import_rejects_invalid_logs() {
+    use movable_tree::{
+        encoding::{write_varint, DecodeError, VERSION},
+        MAX_LAMPORT,
+    };
+    type Node = (u64, u64);
+    const ROOT: Node = (u64::MAX, u64::MAX);
+    /// Encode a log of peer 1 from `(lamport, kind, target, parent)` ops.
+    fn log(ops: &[(u64, u8, Node, Node)]) -> Vec<u8> {
+        let mut buf = vec![VERSION];
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, 1);
+        write_varint(&mut buf, ops.len() as u64);
+        for &(lamport, kind, target, parent) in ops {
+            write_varint(&mut buf, lamport);
+            buf.push(kind);
+            for (lamport, peer) in [target, parent] {
+                write_varint(&mut buf, lamport);
+                write_varint(&mut buf, peer);
+            }
+            if kind == 1 {
+                write_varint(&mut buf, 0);
+            }
+            buf.push(0);
+        }
+        buf
+    }
+    fn check<T: MovableTreeAlgorithm>() {
+        let invalid = DecodeError::Invalid;
+        let mut tree = MovableTree::<T>::new(0);
+        let repeated = log(&[(3, 0, (3, 1), ROOT), (3, 0, (4, 1), ROOT)]);
+        assert_eq!(tree.import(&repeated), Err(invalid("lamport order")));
+        let decreasing = log(&[(3, 0, (3, 1), ROOT), (2, 0, (2, 1), ROOT)]);
+        assert_eq!(tree.import(&decreasing), Err(invalid("lamport order")));
+        let exhausted = log(&[(MAX_LAMPORT + 1, 0, (0, 1), ROOT)]);
+        assert_eq!(tree.import(&exhausted), Err(invalid("lamport")));
+        let root = log(&[(0, 0, ROOT, ROOT)]);
+        assert_eq!(tree.import(&root), Err(invalid("op on the root")));
+        assert!(tree.nodes().is_empty());
+
+        // a move of a node nobody created is valid, as its create may still
+        // arrive from another peer
+        tree.import(&log(&[(0, 1, (5, 2), ROOT)])).unwrap();
+        // as are the moves an EvanTree emits under one ID for a single move
+        let a = (1, 1);
+        let b = (2, 1);
+        let moves = [
+            (1, 0, a, ROOT),
+            (2, 0, b, ROOT),
+            (3, 1, a, b),
+            (3, 1, b, ROOT),
+        ];
+        tree.import(&log(&moves[..3])).unwrap();
+        tree.import(&log(&moves)).unwrap();
+        // a lamport at the limit still leaves IDs for the ops of everyone
+        // who merges it
+        let mut moves = moves.to_vec();
+        moves.push((MAX_LAMPORT, 0, (MAX_LAMPORT, 1), ROOT));
+        tree.import(&log(&moves)).unwrap();
+        let c = tree.create(None);
+        let far = movable_tree::NodeID {
+            lamport: MAX_LAMPORT,
+            peer: 1,
+        };
+        tree.mov(far, c).unwrap();
+        let mut fresh = MovableTree::<T>::new(2);
+        fresh.import(&tree.export()).unwrap();
+        fresh.create(Some(c));
+        tree.import(&fresh.export()).unwrap();
+        assert_eq!(tree.to_string(), fresh.to_string());
+    }
+    check::<EvanTree>();
+    check::<MartinTree>();
+}
+
+#[test]
+fn skewed_clock_leaves_room_for_ids() {
+    let mut tree = MovableTree::<MartinTree>::new(0);
+    let mut skewed = MovableTree::<MartinTree>::with_clock(1, || u64::MAX - 1);
+    let a = skewed.create(None);
+    tree.merge(&skewed);
+    let b = tree.create(None);
+    tree.mov(a, b).unwrap();
+    skewed.import(&tree.export()).unwrap();
+    skewed.create(Some(a));
+    tree.import(&skewed.export()).unwrap();
+    assert_eq!(tree.to_string(), skewed.to_string());
+}
+
+#[test]
+fn peer_collision() {
+    use rand::{rngs::StdRng, SeedableRng};
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut tree = MovableTree::<MartinTree>::with_random_peer(&mut rng);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut clone = MovableTree::<MartinTree>::with_random_peer(&mut rng);
+    assert_eq!(tree.peer(), clone.peer());
+    let mut other = MovableTree::<MartinTree>::new(7);
+    let a = other.create(None);
+    let b = other.create(None);
+    tree.merge(&other);
+    clone.merge(&other);
+
+    // both clients think they are the same peer and make different edits
+    tree.mov(a, b).unwrap();
+    clone.mov(b, a).unwrap();
+    let report = other.merge_with_report(&tree);
+    assert!(report.collisions.is_empty());
+    let report = other.merge_with_report(&clone);
+    assert_eq!(report.collisions.len(), 1);
+    assert_eq!(report.collisions[0].peer, tree.peer());
+    assert_eq!(other.algorithm.parent(a), Some(b));
+
+    let report = tree.merge_with_report(&clone);
+    assert_eq!(report.collisions.len(), 1);
+    assert_eq!(tree.algorithm.parent(a), Some(b));
+    // plain merges and imports report it as well
+    assert_eq!(clone.merge(&tree).collisions.len(), 1);
+    let report = clone.import(&tree.export()).unwrap();
+    assert_eq!(report.collisions[0].peer, tree.peer());
+    assert_eq!(clone.algorithm.parent(b), Some(a));
+    assert_ne!(
+        MovableTree::<EvanTree>::new_random_peer().peer(),
+        movable_tree::ROOT_ID.peer
+    );
+
+    // a third peer relays a move of a node only one of the clients created
+    fn relay<T: MovableTreeAlgorithm>() -> (MovableTree<T>, movable_tree::NodeID) {
+        let mut x = MovableTree::<T>::new(5);
+        let mut x2 = MovableTree::<T>::new(5);
+        let a = x.create(None);
+        x.create(None);
+        let c = x.create(None);
+        x2.create(None);
+        x2.create(Some(a));
+        let mut relay = MovableTree::<T>::new(6);
+        relay.merge(&x);
+        relay.mov(c, a).unwrap();
+        let mut tree = MovableTree::<T>::new(7);
+        tree.merge(&x2);
+        let report = tree.merge_with_report(&relay);
+        assert_eq!(report.collisions.len(), 1);
+        assert_eq!(report.collisions[0].peer, 5);
+        (tree, c)
+    }
+    let (tree, c) = relay::<MartinTree>();
+    assert_eq!(tree.algorithm.parent(c), None);
+    relay::<EvanTree>();
+}
+
+#[test]
+fn user_supplied_keys() {
+    use movable_tree::NodeKey;
+    fn check<T: MovableTreeAlgorithm<u128>>() {
+        let mut tree = MovableTree::<T, u128>::new(0);
+        let (a, b, c) = (0xa, 0xb, 0xc);
+        tree.create_with_id(None, a).unwrap();
+        tree.create_with_id(Some(a), b).unwrap();
+        assert!(tree.create_with_id(None, a).is_err());
+        assert!(tree.create_with_id(None, u128::root()).is_err());
+        assert!(tree.algorithm.contains(b));
+        assert!(!tree.algorithm.contains(0xd));
+
+        let mut tree2 = MovableTree::<T, u128>::new(1);
+        tree2.import(&tree.export()).unwrap();
+        tree2.create_with_id(Some(b), c).unwrap();
+        tree2.mov(a, c).unwrap_err();
+        tree2.mov(b, u128::root()).unwrap();
+        tree.mov(a, b).unwrap_err();
+        tree.merge(&tree2);
+        tree2.merge(&tree);
+
+        assert_eq!(tree.to_string(), tree2.to_string());
+        assert_eq!(tree.algorithm.parent(c), Some(b));
+        assert_eq!(tree.algorithm.parent(b), Some(u128::root()));
+        assert!(tree.is_ancestor_of(b, c));
+        assert!(!tree.is_ancestor_of(a, b));
+        let mut nodes = tree.nodes();
+        nodes.sort();
+        assert_eq!(nodes, vec![a, b, c]);
+    }
+    check::<EvanTree<u128>>();
+    check::<MartinTree<u128>>();
+}
+
+#[test]
+fn concurrent_create_with_same_key() {
+    fn check<T: MovableTreeAlgorithm>() {
+        let root = movable_tree::ROOT_ID;
+        let mut a = MovableTree::<T>::new(0);
+        let mut b = MovableTree::<T>::new(1);
+        let shared = a.create(None);
+        b.merge(&a);
+
+        // b makes more edits so that its create of "Inbox" sorts later
+        b.create(None);
+        let inbox = a.create_with_key(None, b"Inbox").unwrap();
+        let x = a.create(Some(inbox));
+        assert_eq!(b.create_with_key(None, b"Inbox"), Ok(inbox));
+        let y = b.create(Some(inbox));
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.algorithm.parent(inbox), Some(root));
+        assert_eq!(a.algorithm.parent(x), Some(inbox));
+        assert_eq!(a.algorithm.parent(y), Some(inbox));
+        assert_eq!(a.nodes().len(), 5);
+
+        // creating an existing key moves it instead
+        assert_eq!(a.create_with_key(Some(shared), b"Inbox"), Ok(inbox));
+        assert_eq!(a.algorithm.parent(inbox), Some(shared));
+        assert!(a.create_with_key(Some(x), b"Inbox").is_err());
+
+        // concurrent creates under different parents converge to one node
+        // under the parent of the later create, which acts like a move, even
+        // if the earlier one is under the larger parent
+        assert!(y > shared);
+        let archive = a.create_with_key(Some(y), b"Archive").unwrap();
+        b.create(None);
+        assert_eq!(b.create_with_key(Some(shared), b"Archive"), Ok(archive));
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a.to_string(), b.to_string());
+        assert_eq!(a.nodes().len(), 7);
+        assert_eq!(a.algorithm.parent(archive), Some(shared));
+    }
+    check::<EvanTree>();
+    check::<MartinTree>();
+
+    // a node with concurrent creates that Evan's algorithm rescues from a
+    // cycle goes back under the parent of the later create as well
+    let mut a = MovableTree::<EvanTree>::new(0);
+    let mut b = MovableTree::<EvanTree>::new(1);
+    let small = a.create(None);
+    let large = a.create(None);
+    let m = a.create(None);
+    b.merge(&a);
+    b.create(None);
+    let n = a.create_with_key(Some(large), b"n").unwrap();
+    assert_eq!(b.create_with_key(Some(small), b"n"), Ok(n));
+    a.mov(n, m).unwrap();
+    b.mov(m, n).unwrap();
+    let report = a.merge_with_report(&b);
+    assert_eq!(report.rescued, vec![(n, small)]);
+    b.merge(&a);
+    assert_eq!(a.to_string(), b.to_string());
+    assert_eq!(a.algorithm.parent(n), Some(small));
+    assert_eq!(a.algorithm.parent(m), Some(n));
+}
+
+#[test]
+fn state_sync_matches_op_sync() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn pair<T>(v: &mut [T], a: usize, b: usize) -> (&mut T, &T) {
+        if a < b {
+            let (l, r) = v.split_at_mut(b);
+            (&mut l[a], &r[0])
+        } else {
+            let (l, r) = v.split_at_mut(a);
+            (&mut r[0], &l[b])
+        }
+    }
+
+    for seed in 0..8 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut by_ops: Vec<_> = (0..3).map(MovableTree::<EvanTree>::new).collect();
+        let mut by_state: Vec<_> = (0..3).map(MovableTree::<EvanTree>::new).collect();
+        for _ in 0..300 {
+            let a = rng.gen_range(0..3);
+            let mut nodes = by_ops[a].nodes();
+            nodes.sort();
+            match rng.gen_range(0..5) {
+                0 => {
+                    let parent = nodes.get(rng.gen_range(0..=nodes.len())).copied();
+                    assert_eq!(by_ops[a].create(parent), by_state[a].create(parent));
+                }
+                1 | 2 if nodes.len() > 1 => {
+                    let target = nodes[rng.gen_range(0..nodes.len())];
+                    let parent = nodes[rng.gen_range(0..nodes.len())];
+                    assert_eq!(
+                        by_ops[a].mov(target, parent),
+                        by_state[a].mov(target, parent)
+                    );
+                }
+                3 if nodes.len() > 1 => {
+                    let target = nodes[rng.gen_range(0..nodes.len())];
+                    let edges = by_ops[a].algorithm.edges(target);
+                    if let Some(&(parent, _)) = edges.get(rng.gen_range(0..=edges.len())) {
+                        assert_eq!(
+                            by_ops[a].remove_edge(target, parent),
+                            by_state[a].remove_edge(target, parent)
+                        );
+                    }
+                }
+                _ => {
+                    let b = (a + rng.gen_range(1..3)) % 3;
+                    let (to, from) = pair(&mut by_ops, a, b);
+                    to.merge(from);
+                    let (to, from) = pair(&mut by_state, a, b);
+                    if rng.gen_bool(0.5) {
+                        to.merge_state(from);
+                    } else {
+                        let delta = from.export_delta(to.algorithm.version());
+                        to.import_state(&delta).unwrap();
+                    }
+                }
+            }
+            assert_eq!(by_ops[a].to_string(), by_state[a].to_string());
+        }
+
+        let state = by_state[0].export_state();
+        let mut fresh = MovableTree::<EvanTree>::new(9);
+        fresh.import_state(&state).unwrap();
+        assert_eq!(fresh.to_string(), by_state[0].to_string());
+        // nothing is missing, so the delta carries no edges
+        let delta = fresh.export_delta(by_state[0].algorithm.version());
+        let mut empty = MovableTree::<EvanTree>::new(8);
+        empty.import_state(&delta).unwrap();
+        assert!(empty.nodes().is_empty());
+        assert!(fresh.import_state(&state[..state.len() - 1]).is_err());
+    }
+}
+
+#[test]
+fn remove_edge() {
+    use movable_tree::ROOT_ID;
+    let mut a = MovableTree::<EvanTree>::new(0);
+    let x = a.create(None);
+    let y = a.create(None);
+    let z = a.create(Some(x));
+    a.mov(x, y).unwrap();
+    assert_eq!(a.algorithm.edges(x).len(), 2);
+
+    // falls back to the older edge
+    a.remove_edge(x, y).unwrap();
+    assert_eq!(a.algorithm.parent(x), Some(ROOT_ID));
+    assert!(a.remove_edge(x, y).is_err());
+
+    // a node without edges is detached together with its subtree
+    a.remove_edge(x, ROOT_ID).unwrap();
+    assert_eq!(a.algorithm.parent(x), None);
+    assert!(!a.is_ancestor_of(ROOT_ID, z));
+    assert!(!a.to_string().contains(&x.to_string()));
+    a.mov(x, y).unwrap();
+    assert_eq!(a.algorithm.parent(x), Some(y));
+
+    // a concurrent write with a larger op ID wins over the removal
+    let mut b = MovableTree::<EvanTree>::new(1);
+    b.merge(&a);
+    a.remove_edge(x, y).unwrap();
+    b.create(None);
+    b.create(None);
+    b.mov(x, y).unwrap();
+    let report = a.merge_with_report(&b);
+    b.merge(&a);
+    assert_eq!(a.to_string(), b.to_string());
+    assert!(report.rescued.is_empty());
+    assert_eq!(a.algorithm.parent(x), Some(y));
+    let mut c = MovableTree::<EvanTree>::new(2);
+    c.import(&a.export()).unwrap();
+    assert_eq!(c.to_string(), a.to_string());
+}
+
+#[cfg(feature = "p2pdb")]
+#[test]
+fn replay_p2pdb_fixtures() {
+    use movable_tree::p2pdb::{self, Row};
+    use std::collections::BTreeMap;
+
+    for name in ["two-peers", "three-peers", "four-peers"] {
+        // recorded with js/fixtures.js
+        let path = format!(
+            "{}/tests/fixtures/p2pdb/{}.json",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        let mut fixture: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let rows: Vec<Row> = serde_json::from_value(fixture["rows"].take()).unwrap();
+        let parents: BTreeMap<String, Option<String>> =
+            serde_json::from_value(fixture["parents"].take()).unwrap();
+        let check = |tree: &MovableTree<EvanTree>| {
+            for (node, parent) in &parents {
+                let id = p2pdb::parse_node_name(node).unwrap();
+                let actual = tree.algorithm.parent(id).map(p2pdb::node_name);
+                assert_eq!(actual.as_ref(), parent.as_ref(), "{} in {}", node, name);
+            }
+        };
+
+        let mut tree = MovableTree::<EvanTree>::new(0);
+        tree.algorithm.use_js_ties();
+        tree.import_rows(&rows).unwrap();
+        check(&tree);
+        // the rows of each edge are last-writer-wins, so their order doesn't
+        // matter
+        let reversed: Vec<_> = rows.iter().rev().cloned().collect();
+        let mut other = MovableTree::<EvanTree>::new(1);
+        other.algorithm.use_js_ties();
+        other.import_rows(&reversed).unwrap();
+        check(&other);
+
+        // exported rows keep the names and replay to the same tree
+        tree.mov(
+            p2pdb::parse_node_name(&rows[1].id).unwrap(),
+            p2pdb::parse_node_name(&rows[0].id).unwrap(),
+        )
+        .unwrap_or_default();
+        let json = serde_json::to_string(&tree.export_rows()).unwrap();
+        let exported: Vec<Row> = serde_json::from_str(&json).unwrap();
+        assert!(exported.iter().all(|row| parents.contains_key(&row.id)));
+        let mut replayed = MovableTree::<EvanTree>::new(2);
+        replayed.algorithm.use_js_ties();
+        replayed.import_rows(&exported).unwrap();
+        assert_eq!(replayed.to_string(), tree.to_string());
+    }
+
+    let row = |id: &str, key: &str| Row {
+        id: id.into(),
+        key: key.into(),
+        value: Some(0),
+        peer: "Peer 1".into(),
+        timestamp: 0,
+    };
+    // the JS tie-breaks rescue the node with the smaller ID from a cycle
+    // first, while trees of this crate rescue the larger one
+    let swap = |js_ties: bool| {
+        let mut a = MovableTree::<EvanTree>::new(0);
+        let mut b = MovableTree::<EvanTree>::new(1);
+        if js_ties {
+            a.algorithm.use_js_ties();
+            b.algorithm.use_js_ties();
+        }
+        let x = a.create(None);
+        let y = a.create(None);
+        b.merge(&a);
+        a.mov(y, x).unwrap();
+        b.mov(x, y).unwrap();
+        a.merge(&b);
+        (a.algorithm.parent(x), y)
+    };
+    let (parent, y) = swap(false);
+    assert_eq!(parent, Some(y));
+    assert_eq!(swap(true).0, Some(movable_tree::ROOT_ID));
+
+    let mut tree = MovableTree::<EvanTree>::new(0);
+    assert!(tree.import_rows(&[row("(ROOT)", "a")]).is_err());
+    assert!(tree
+        .import_rows(&[row("a name that is too long", "(ROOT)")])
+        .is_err());
+}
+
+#[test]
+fn selective_undo() {
+    use movable_tree::{ID, ROOT_ID};
+    let mut a = MovableTree::<MartinTree>::new(0);
+    let x = a.create(None);
+    let y = a.create(None);
+    let z = a.create(None);
+    a.mov(z, x).unwrap();
+    let to_x = a.history(z).last().unwrap().id;
+    a.mov(z, y).unwrap();
+
+    // the later move stays in the log, but the node goes back to where it
+    // was before the undone op
+    let undo = a.undo(to_x).unwrap();
+    assert_eq!(a.algorithm.parent(z), Some(ROOT_ID));
+    // undoing the undo redoes the move it reverted
+    a.undo(undo).unwrap();
+    assert_eq!(a.algorithm.parent(z), Some(y));
+    // creates and unknown ops can't be undone
+    assert!(a.undo(a.history(x)[0].id).is_err());
+    assert!(a
+        .undo(ID {
+            lamport: 100,
+            peer: 0
+        })
+        .is_err());
+
+    // a concurrent move ordered before the undone op changes what it undoes
+    let mut b = MovableTree::<MartinTree>::new(1);
+    b.merge(&a);
+    b.mov(z, x).unwrap();
+    let to_x = b.history(z).last().unwrap().id;
+    assert_eq!(b.algorithm.parent_before(to_x), Some((z, Some(y))));
+    a.mov(z, ROOT_ID).unwrap();
+    b.merge(&a);
+    assert_eq!(b.algorithm.parent(z), Some(x));
+    b.undo(to_x).unwrap();
+    assert_eq!(b.algorithm.parent(z), Some(ROOT_ID));
+    a.merge(&b);
+    assert_eq!(a.to_string(), b.to_string());
+
+    // the old parent is no longer valid once it is under the node
+    a.mov(z, x).unwrap();
+    a.mov(z, y).unwrap();
+    let from_x = a.history(z).last().unwrap().id;
+    b.merge(&a);
+    b.mov(x, z).unwrap();
+    let mut c = MovableTree::<MartinTree>::new(2);
+    c.merge(&b);
+    assert!(c.undo(from_x).is_err());
+    // a concurrent undo wins over the move that would make it a cycle
+    a.undo(from_x).unwrap();
+    a.merge(&b);
+    b.merge(&a);
+    assert_eq!(a.to_string(), b.to_string());
+    assert_eq!(a.algorithm.parent(z), Some(x));
+}
+
+#[test]
+fn revert_peer() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn run<T: MovableTreeAlgorithm>(
+        creates: bool,
+        revert_peer: impl Fn(&mut MovableTree<T>, u64, u64) -> Vec<movable_tree::ID>,
+    ) {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut a = MovableTree::<T>::new(0);
+        let mut nodes = vec![];
+        for _ in 0..20 {
+            let parent = nodes.get(rng.gen_range(0..=nodes.len())).copied();
+            nodes.push(a.create(parent));
+        }
+        let mut bad = MovableTree::<T>::new(1);
+        let mut good = MovableTree::<T>::new(2);
+        bad.merge(&a);
+        good.merge(&a);
+        // kept, as it is older than the reverted ops
+        bad.mov(nodes[5], nodes[1]).unwrap_or_default();
+        let since = bad.new_id().lamport;
+        good.merge(&bad);
+        for _ in 0..50 {
+            let target = nodes[rng.gen_range(0..nodes.len())];
+            let parent = nodes[rng.gen_range(0..nodes.len())];
+            bad.mov(target, parent).unwrap_or_default();
+            if creates {
+                let child = bad.create(Some(target));
+                bad.create(Some(child));
+                bad.mov(parent, child).unwrap_or_default();
+            }
+        }
+        for _ in 0..10 {
+            let target = nodes[rng.gen_range(0..nodes.len())];
+            let parent = nodes[rng.gen_range(0..nodes.len())];
+            good.mov(target, parent).unwrap_or_default();
+        }
+
+        a.merge(&bad);
+        a.merge(&good);
+        assert_ne!(a.to_string(), good.to_string());
+        assert!(!revert_peer(&mut a, 1, since).is_empty());
+        assert_eq!(a.to_string(), good.to_string());
+
+        // the reverts sync like any other op
+        let mut c = MovableTree::<T>::new(3);
+        c.merge(&bad);
+        c.merge(&a);
+        assert_eq!(c.to_string(), good.to_string());
+
+        a.quarantine_peer(1);
+        bad.mov(nodes[3], nodes[4]).unwrap_or_default();
+        bad.create(None);
+        c.merge(&bad);
+        let report = a.merge_with_report(&c);
+        assert_eq!(report.quarantined, vec![1]);
+        assert_eq!(a.to_string(), good.to_string());
+    }
+
+    run::<MartinTree>(false, |t, peer, since| t.revert_peer(peer, since));
+    run::<EvanTree>(false, |t, peer, since| t.revert_peer(peer, since));
+    // only Evan's algorithm can take the nodes the peer created out of the
+    // tree again
+    run::<EvanTree>(true, |t, peer, since| t.revert_peer(peer, since));
+}
+
+#[test]
+fn revert_peer_with_dependent_ops() {
+    use movable_tree::model::check_invariants;
+
+    fn run<T: MovableTreeAlgorithm>(
+        revert_peer: impl Fn(&mut MovableTree<T>, u64, u64) -> Vec<movable_tree::ID>,
+        // whether the moves of and under the left out node are skipped, or
+        // the move of the node puts it back in the tree
+        skipped: bool,
+    ) {
+        let check = |t: &mut MovableTree<T>, x, n, m| {
+            check_invariants(t).unwrap();
+            if skipped {
+                assert_eq!(t.algorithm.parent(x), Some(movable_tree::ROOT_ID));
+            } else {
+                assert_eq!(t.algorithm.parent(x), Some(n));
+                assert_eq!(t.algorithm.parent(n), Some(m));
+            }
+        };
+        let mut a = MovableTree::<T>::new(0);
+        let m = a.create(None);
+        let x = a.create(None);
+        let mut bad = MovableTree::<T>::new(1);
+        bad.merge(&a);
+        let since = bad.new_id().lamport;
+        let n = bad.create(None);
+        // a good peer builds on the node the bad peer created, and a relays
+        // its ops
+        let mut good = MovableTree::<T>::new(2);
+        good.merge(&bad);
+        good.mov(n, m).unwrap();
+        good.mov(x, n).unwrap();
+        a.merge(&good);
+        revert_peer(&mut a, 1, since);
+        check(&mut a, x, n, m);
+
+        // the reverts sync like any other op
+        let mut c = MovableTree::<T>::new(3);
+        c.merge(&a);
+        assert_eq!(c.to_string(), a.to_string());
+
+        // a quarantined peer's create is left out of later merges, and so
+        // are the moves of and under it
+        let mut d = MovableTree::<T>::new(4);
+        d.quarantine_peer(1);
+        let report = d.merge_with_report(&good);
+        assert_eq!(report.quarantined, vec![1]);
+        check(&mut d, x, n, m);
+    }
+
+    run::<MartinTree>(|t, peer, since| t.revert_peer(peer, since), true);
+    run::<EvanTree>(|t, peer, since| t.revert_peer(peer, since), false);
+}
+
+#[test]
+fn compare_algorithms() {
+    use movable_tree::{
+        analysis::{compare, Step},
+        NodeID, ROOT_ID,
+    };
+    let setup = [
+        Step::Create {
+            peer: 0,
+            parent: None,
+        },
+        Step::Create {
+            peer: 0,
+            parent: None,
+        },
+        Step::Create {
+            peer: 0,
+            parent: None,
+        },
+        Step::Sync { from: 0, to: 1 },
+    ];
+
+    // concurrent moves that would form a cycle: one of them has to lose
+    let mut trace = setup.to_vec();
+    trace.extend([
+        Step::Move {
+            peer: 0,
+            target: 1,
+            parent: Some(0),
+        },
+        Step::Move {
+            peer: 1,
+            target: 0,
+            parent: Some(1),
+        },
+    ]);
+    let c = compare(2, &trace);
+    assert_eq!((c.nodes, c.rejected, c.superseded), (3, 0, 0));
+    assert_eq!((c.martin.kept, c.martin.overridden), (1, 1));
+    assert_eq!((c.evan.kept, c.evan.overridden), (1, 1));
+    // Kleppmann applies the smaller op ID first and skips the other move,
+    // while Evan reattaches node 1, the larger ID, to the root first and
+    // keeps the move of peer 1
+    let x = NodeID {
+        lamport: 0,
+        peer: 0,
+    };
+    let y = NodeID {
+        lamport: 1,
+        peer: 0,
+    };
+    assert_eq!(c.diverged.len(), 2);
+    assert_eq!(c.diverged[0].node, x);
+    assert_eq!(c.diverged[0].martin, Some(ROOT_ID));
+    assert_eq!(c.diverged[0].evan, Some(y));
+    assert_eq!(c.diverged[1].node, y);
+    assert_eq!(c.diverged[1].martin, Some(x));
+    assert_eq!(c.diverged[1].evan, Some(ROOT_ID));
+
+    // a move replaced by a later move of the same peer isn't a conflict, and
+    // a node peer 1 hasn't seen yet can't be moved by it
+    let mut trace = setup.to_vec();
+    trace.extend([
+        Step::Move {
+            peer: 0,
+            target: 0,
+            parent: Some(1),
+        },
+        Step::Move {
+            peer: 0,
+            target: 0,
+            parent: Some(2),
+        },
+        Step::Create {
+            peer: 0,
+            parent: None,
+        },
+        Step::Move {
+            peer: 1,
+            target: 3,
+            parent: Some(1),
+        },
+        Step::Move {
+            peer: 1,
+            target: 1,
+            parent: Some(2),
+        },
+    ]);
+    let c = compare(2, &trace);
+    assert_eq!((c.nodes, c.rejected, c.superseded), (4, 1, 1));
+    assert_eq!((c.martin.kept, c.martin.overridden), (2, 0));
+    assert_eq!(c.martin, c.evan);
+    assert!(c.diverged.is_empty());
+}
+
+#[test]
+fn simulated_network() {
+    use movable_tree::sim::{random_edit, Config, Sim};
+
+    fn run<T: MovableTreeAlgorithm>(config: &Config, seed: u64) {
+        let mut sim = Sim::<T>::new(config.clone(), seed);
+        sim.run(200, &mut random_edit);
+        sim.settle();
+        sim.assert_converged();
+
+        let replayed = Sim::<T>::replay(config.peers, sim.schedule());
+        assert_eq!(replayed.schedule(), sim.schedule());
+        for (a, b) in replayed.peers.iter().zip(&sim.peers) {
+            assert_eq!(a.to_string(), b.to_string());
+        }
+    }
+
+    let harsh = Config {
+        peers: 5,
+        loss: 0.5,
+        duplicate: 0.3,
+        latency: 0..=20,
+        partition: 0.1,
+        heal: 0.02,
+        ..Default::default()
+    };
+    for seed in 0..10 {
+        run::<MartinTree>(&Config::default(), seed);
+        run::<EvanTree>(&Config::default(), seed);
+        run::<MartinTree>(&harsh, seed);
+        run::<EvanTree>(&harsh, seed);
+    }
+}
+
+#[test]
+fn model_check() {
+    use movable_tree::model::{check, check_with, moves, Move};
+    let m = |target, parent| Move {
+        target,
+        parent: Some(parent),
+    };
+    // every pair of concurrent moves between 3 nodes
+    let all = moves(3);
+    for a in &all {
+        for b in &all {
+            let scripts = [vec![*a], vec![*b]];
+            if let Err(e) = check::<MartinTree>(3, &scripts) {
+                panic!("{}", e);
+            }
+            if let Err(e) = check::<EvanTree>(3, &scripts) {
+                panic!("{}", e);
+            }
+            if let Err(e) = check_with(3, &scripts, MartinTree::with_ancestor_index) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    // peers rotating four nodes into cycles, `examples/model_check.rs`
+    // checks three moves each for three peers
+    for scripts in [
+        vec![vec![m(0, 1)], vec![m(1, 2)], vec![m(2, 0)]],
+        vec![vec![m(0, 1), m(2, 3)], vec![m(3, 2), m(1, 0)]],
+    ] {
+        check::<MartinTree>(4, &scripts).unwrap_or_else(|e| panic!("{}", e));
+        check::<EvanTree>(4, &scripts).unwrap_or_else(|e| panic!("{}", e));
+        check_with(4, &scripts, MartinTree::with_ancestor_index)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+#[test]
+fn scenarios() {
+    use movable_tree::scenario::{parse, run};
+    let swap = "
+        peer A; peer B
+        A: create x under root
+        A: create y
+        sync A B
+        A: move y under x
+        B: move x under y
+        expect A:
+          x
+            y
+        sync all
+        expect:
+          {}
+    ";
+    // Kleppmann keeps the move with the smaller op ID, while Evan reattaches
+    // the node with the larger ID first
+    run::<MartinTree>(&swap.replace("{}", "x\n            y")).unwrap();
+    run::<EvanTree>(&swap.replace("{}", "y\n            x")).unwrap();
+
+    let err = run::<MartinTree>(&swap.replace("{}", "y\n            x")).unwrap_err();
+    assert_eq!(err.line, 12);
+    assert_eq!(
+        err.to_string(),
+        "line 12: tree of A doesn't match, - expected + actual:\n- y\n-   x\n+ x\n+   y\n"
+    );
+
+    let err =
+        run::<EvanTree>("peer A\nA: create x\nA: create y under x\nA: move x under y").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "line 4: A rejects moving x under y as a cycle"
+    );
+    let err = parse("peer A\nA: jump x").unwrap_err();
+    assert_eq!(err.to_string(), "line 2: unknown statement: `A: jump x`");
+    let err = run::<MartinTree>("peer A; peer B\nA: create x\nB: move x under root").unwrap_err();
+    assert_eq!(err.to_string(), "line 3: B doesn't know node x");
+}